@@ -1,7 +1,16 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use http::header::AUTHORIZATION;
+use itertools::Itertools;
+use prost::Message;
 use reqwest::RequestBuilder;
-use serde::{Deserialize, Deserializer};
-use worker::Env;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use worker::{Env, Response};
+
+use crate::upstream::Upstream;
+use realtime::{trip_update::stop_time_update::ScheduleRelationship, FeedMessage, TripUpdate};
 
 pub fn add_auth_header(env: &Env) -> Option<impl FnOnce(RequestBuilder) -> RequestBuilder> {
     env.secret("key").ok().map(|key| {
@@ -12,7 +21,7 @@ pub fn add_auth_header(env: &Env) -> Option<impl FnOnce(RequestBuilder) -> Reque
 }
 
 pub struct TimesResult {
-    pub times: Vec<String>,
+    pub times: Vec<DateTime<Utc>>,
 }
 
 impl<'de> Deserialize<'de> for TimesResult {
@@ -29,7 +38,7 @@ impl<'de> Deserialize<'de> for TimesResult {
         #[derive(Deserialize)]
         #[serde(rename_all = "camelCase")]
         struct StopEvent {
-            departure_time_planned: String,
+            departure_time_planned: DateTime<Utc>,
         }
 
         let departures = Departures::deserialize(deserializer)?;
@@ -42,6 +51,378 @@ impl<'de> Deserialize<'de> for TimesResult {
     }
 }
 
+/// Mode of transport, from the product class that the Transport Open Data 'Trip Planner APIs' API
+/// gives it.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Train,
+    Metro,
+    LightRail,
+    Bus,
+    Coach,
+    Ferry,
+    SchoolBus,
+    Walk,
+    Cycle,
+    Other,
+}
+
+/// Modes that the departure monitor can show, which it is asked to leave out unless they are
+/// chosen.
+const DEPARTURE_MODES: [Mode; 7] = [
+    Mode::Train,
+    Mode::Metro,
+    Mode::LightRail,
+    Mode::Bus,
+    Mode::Coach,
+    Mode::Ferry,
+    Mode::SchoolBus,
+];
+
+/// Modes that the departure monitor shows when no `modes` parameter is given.
+const DEFAULT_DEPARTURE_MODES: [Mode; 2] = [Mode::Bus, Mode::SchoolBus];
+
+impl Mode {
+    pub const fn from_class(class: Option<u16>) -> Self {
+        match class {
+            Some(1) => Self::Train,
+            Some(2) => Self::Metro,
+            Some(4) => Self::LightRail,
+            Some(5) => Self::Bus,
+            Some(7) => Self::Coach,
+            Some(9) => Self::Ferry,
+            Some(11) => Self::SchoolBus,
+            Some(99 | 100) => Self::Walk,
+            Some(107) => Self::Cycle,
+            _ => Self::Other,
+        }
+    }
+
+    /// Product class of the mode, which is also the number of its `exclMOT_` flag.
+    const fn class(self) -> Option<u16> {
+        match self {
+            Self::Train => Some(1),
+            Self::Metro => Some(2),
+            Self::LightRail => Some(4),
+            Self::Bus => Some(5),
+            Self::Coach => Some(7),
+            Self::Ferry => Some(9),
+            Self::SchoolBus => Some(11),
+            Self::Walk => Some(100),
+            Self::Cycle => Some(107),
+            Self::Other => None,
+        }
+    }
+
+    /// Read the modes for the departure monitor from the `modes` parameter of a request, written
+    /// as a comma-separated list such as `bus,school_bus`.
+    pub fn from_pairs(
+        pairs: &HashMap<String, String>,
+    ) -> Result<Vec<Self>, worker::Result<Response>> {
+        let Some(modes) = pairs.get("modes") else {
+            return Ok(DEFAULT_DEPARTURE_MODES.to_vec());
+        };
+        modes
+            .split(',')
+            .map(|name| {
+                DEPARTURE_MODES
+                    .into_iter()
+                    .find(|mode| mode.name() == name.trim())
+                    .ok_or_else(|| {
+                        Response::error(
+                            format!("The `modes` parameter must be a comma-separated list of modes from `train`, `metro`, `light_rail`, `bus`, `coach`, `ferry` and `school_bus`, but `{name}` is not one of them."),
+                            400,
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Train => "train",
+            Self::Metro => "metro",
+            Self::LightRail => "light_rail",
+            Self::Bus => "bus",
+            Self::Coach => "coach",
+            Self::Ferry => "ferry",
+            Self::SchoolBus => "school_bus",
+            Self::Walk => "walk",
+            Self::Cycle => "cycle",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// When the departure monitor lists departures from, read from the `date`, `time` and `dep_arr`
+/// parameters of a request.
+#[derive(Clone, Copy, Default)]
+pub struct DepartureTime {
+    date: Option<NaiveDate>,
+    time: Option<u32>,
+    /// Whether to list vehicles arriving around the time rather than leaving.
+    arrivals: bool,
+}
+
+impl DepartureTime {
+    pub fn from_pairs(pairs: &HashMap<String, String>) -> Result<Self, worker::Result<Response>> {
+        let arrivals = match pairs.get("dep_arr").map(String::as_str) {
+            None | Some("dep") => false,
+            Some("arr") => true,
+            Some(_) => {
+                return Err(Response::error(
+                    "The `dep_arr` parameter must be `dep` or `arr`.",
+                    400,
+                ));
+            }
+        };
+        Ok(Self {
+            date: date(pairs, "date")?,
+            time: time(pairs, "time")?,
+            arrivals,
+        })
+    }
+
+    /// Parameters for the departure monitor. Without a date or time it lists departures from now,
+    /// with a time it uses today, and with a date it starts from midnight.
+    fn query(self) -> Vec<(String, String)> {
+        let mut query = vec![(
+            "depArrMacro".to_string(),
+            if self.arrivals { "arr" } else { "dep" }.to_string(),
+        )];
+        if self.date.is_some() || self.time.is_some() {
+            let date = self.date.unwrap_or_else(today);
+            let time = self.time.unwrap_or_default();
+            query.push(("itdDate".to_string(), date.format("%Y%m%d").to_string()));
+            query.push((
+                "itdTime".to_string(),
+                format!("{:02}{:02}", time / 3600, time / 60 % 60),
+            ));
+        }
+        query
+    }
+}
+
+/// Departures from the stop `id`, from the departure monitor of the Transport Open Data 'Trip
+/// Planner APIs' API, read as `T`.
+pub async fn fetch_departures<T: DeserializeOwned>(
+    env: &Env,
+    id: &str,
+    modes: &[Mode],
+    when: DepartureTime,
+) -> Result<T, worker::Result<Response>> {
+    let query = DEPARTURE_MODES
+        .into_iter()
+        .filter(|mode| !modes.contains(mode))
+        .filter_map(Mode::class)
+        .map(|class| (format!("exclMOT_{class}"), "1".to_string()))
+        .chain(when.query())
+        .collect_vec();
+    Upstream::new("Trip Planner APIs", "v1/tp/departure_mon")
+        .queries([
+            ("outputFormat", "rapidJSON"),
+            ("coordOutputFormat", "EPSG:4326"),
+            ("mode", "direct"),
+            ("type_dm", "stop"),
+            ("name_dm", id),
+            ("departureMonitorMacro", "true"),
+            ("excludedMeans", "checkbox"),
+            ("TfNSWDM", "true"),
+            ("version", "10.2.1.42"),
+        ])
+        .queries(query)
+        .json(env)
+        .await
+}
+
+/// Parse the date parameter `name`, written as `YYYY-MM-DD`.
+pub fn date(
+    pairs: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<NaiveDate>, worker::Result<Response>> {
+    pairs
+        .get(name)
+        .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .transpose()
+        .map_err(|_| {
+            Response::error(
+                format!("The `{name}` parameter must be a date written as YYYY-MM-DD."),
+                400,
+            )
+        })
+}
+
+/// Read a time of day written as `HH:MM` from the parameter `name`, in seconds since midnight.
+pub fn time(
+    pairs: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<u32>, worker::Result<Response>> {
+    pairs
+        .get(name)
+        .map(|time| NaiveTime::parse_from_str(time, "%H:%M"))
+        .transpose()
+        .map(|time| time.map(|time| time.num_seconds_from_midnight()))
+        .map_err(|_| {
+            Response::error(
+                format!("The `{name}` parameter must be a time written as HH:MM."),
+                400,
+            )
+        })
+}
+
+/// Realtime trip updates for buses, from the Transport Open Data 'Public Transport - Realtime Trip
+/// Updates API' API.
+pub const TRIP_UPDATES: Feed = Feed {
+    path: "v1/gtfs/realtime/buses",
+    name: "Public Transport - Realtime Trip Updates API",
+};
+
+/// Realtime vehicle positions for buses, from the Transport Open Data 'Public Transport - Realtime
+/// Vehicle Positions API' API.
+pub const VEHICLE_POSITIONS: Feed = Feed {
+    path: "v1/gtfs/vehiclepos/buses",
+    name: "Public Transport - Realtime Vehicle Positions API",
+};
+
+/// A GTFS-realtime feed from the Transport Open Data APIs.
+pub struct Feed {
+    path: &'static str,
+    name: &'static str,
+}
+
+impl Feed {
+    pub async fn fetch(&self, env: &Env) -> Result<FeedMessage, worker::Result<Response>> {
+        let Self { path, name } = self;
+        let bytes = Upstream::new(name, path).bytes(env).await?;
+        FeedMessage::decode(bytes.as_slice()).map_err(|_| {
+            Response::error(
+                format!(
+                    "Error while decoding the response from the Transport Open Data '{name}' API."
+                ),
+                500,
+            )
+        })
+    }
+}
+
+/// Realtime delays of a trip at one of its stops, in seconds.
+#[derive(Clone, Copy, Default)]
+pub struct Delay {
+    pub arrival: Option<i32>,
+    pub departure: Option<i32>,
+    /// Whether the vehicle will not stop here, although the timetable says it does.
+    pub skipped: bool,
+}
+
+/// Delays at each of `stops`, the stop sequences where known and stop IDs of a trip's stops in
+/// order, from its trip update. Updates are matched by stop sequence where both have one, and
+/// otherwise by stop ID at or after the previous match, as a stop can be visited more than once. A
+/// stop without an update of its own keeps the delay of the last stop before it that has one, as
+/// GTFS-realtime specifies.
+pub fn stop_delays(update: &TripUpdate, stops: &[(Option<u32>, &str)]) -> Vec<Delay> {
+    let mut updates = vec![None; stops.len()];
+    let mut from = 0;
+    for stop_time_update in &update.stop_time_update {
+        let position = stops
+            .iter()
+            .position(|(sequence, _)| {
+                sequence.is_some() && *sequence == stop_time_update.stop_sequence
+            })
+            .or_else(|| {
+                let stop_id = stop_time_update.stop_id.as_deref()?;
+                stops[from..]
+                    .iter()
+                    .position(|(_, stop)| *stop == stop_id)
+                    .map(|position| from + position)
+            });
+        if let Some(position) = position {
+            updates[position] = Some(stop_time_update);
+            from = position;
+        }
+    }
+    let mut latest = None;
+    updates
+        .into_iter()
+        .map(|stop_time_update| match stop_time_update {
+            Some(stop_time_update) => {
+                let arrival = stop_time_update.arrival.and_then(|event| event.delay);
+                let departure = stop_time_update.departure.and_then(|event| event.delay);
+                if arrival.is_some() || departure.is_some() {
+                    latest = departure.or(arrival);
+                }
+                Delay {
+                    arrival: arrival.or(departure),
+                    departure: departure.or(arrival),
+                    skipped: stop_time_update.schedule_relationship()
+                        == ScheduleRelationship::Skipped,
+                }
+            }
+            None => Delay {
+                arrival: latest,
+                departure: latest,
+                skipped: false,
+            },
+        })
+        .collect()
+}
+
+/// Time zone of the TfNSW timetables, in which their dates and times of day are written.
+pub const TIMEZONE: Tz = Tz::Australia__Sydney;
+
+/// Today's date in [`TIMEZONE`].
+pub fn today() -> NaiveDate {
+    Utc::now().with_timezone(&TIMEZONE).date_naive()
+}
+
+/// Start of the service day `date`, from which GTFS times of day are counted. GTFS defines this as
+/// 12 hours before noon, which is midnight except on days when daylight saving starts or ends.
+pub fn service_day_start(date: NaiveDate) -> Option<DateTime<Utc>> {
+    let noon = TIMEZONE
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+    Some(noon.with_timezone(&Utc) - TimeDelta::hours(12))
+}
+
+/// The time `seconds` after the start of the service day `date`.
+pub fn service_time(date: NaiveDate, seconds: u32) -> Option<DateTime<Utc>> {
+    Some(service_day_start(date)? + TimeDelta::seconds(seconds.into()))
+}
+
+/// `time` written as GTFS writes a time of day, as `HH:MM:SS` since the start of the service day
+/// `date`, so that a trip that runs past midnight is given a time such as `25:10:00`.
+pub fn gtfs_time(date: NaiveDate, time: DateTime<Utc>) -> Option<String> {
+    let seconds = (time - service_day_start(date)?).num_seconds();
+    (seconds >= 0).then(|| {
+        format!(
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    })
+}
+
+/// Parse the `tz` parameter, the IANA time zone in which times are given, such as
+/// `Australia/Sydney`. Times are given in [`TIMEZONE`] when there is no `tz` parameter.
+pub fn timezone(pairs: &HashMap<String, String>) -> Result<Tz, worker::Result<Response>> {
+    pairs.get("tz").map_or(Ok(TIMEZONE), |tz| {
+        tz.parse().map_err(|_| {
+            Response::error(
+                "The `tz` parameter must be an IANA time zone, such as `Australia/Sydney`.",
+                400,
+            )
+        })
+    })
+}
+
+/// Types for GTFS-realtime feeds, generated by `prost-build` from `proto/gtfs-realtime.proto`. Only
+/// the lints that the generated names and comments trip are allowed.
+#[allow(
+    clippy::enum_variant_names,
+    clippy::doc_lazy_continuation,
+    clippy::doc_overindented_list_items
+)]
 pub mod realtime {
     include!(concat!(env!("OUT_DIR"), "/transit_realtime.rs"));
 }
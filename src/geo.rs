@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+/// Mean radius of the Earth in metres.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Point {
+    pub const fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+        }
+    }
+
+    /// Great-circle distance to `other` in metres.
+    pub fn distance(self, other: Self) -> f64 {
+        let (lat_a, lat_b) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat_b - lat_a;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a =
+            (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Offset of `other` from this point in metres east and north, on a flat approximation that
+    /// is accurate over the few hundred metres between two shape points.
+    fn offset(self, other: Self) -> (f64, f64) {
+        let x = (other.longitude - self.longitude).to_radians()
+            * self.latitude.to_radians().cos()
+            * EARTH_RADIUS;
+        let y = (other.latitude - self.latitude).to_radians() * EARTH_RADIUS;
+        (x, y)
+    }
+}
+
+/// A polyline, such as a route shape, with the distance along it to each of its points.
+pub struct Line {
+    points: Vec<Point>,
+    distances: Vec<f64>,
+}
+
+/// Where a point lands when snapped onto a [`Line`].
+#[derive(Clone, Copy, Debug)]
+pub struct Snapped {
+    /// Distance along the line to the snapped point in metres.
+    pub along: f64,
+    /// Distance from the original point to the line in metres.
+    pub offset: f64,
+}
+
+impl Line {
+    pub fn new(points: Vec<Point>) -> Self {
+        let distances = points
+            .iter()
+            .scan((0.0, None::<Point>), |(total, previous), point| {
+                if let Some(previous) = previous {
+                    *total += previous.distance(*point);
+                }
+                *previous = Some(*point);
+                Some(*total)
+            })
+            .collect();
+        Self { points, distances }
+    }
+
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    /// Snap `point` onto the nearest segment of the line.
+    pub fn snap(&self, point: Point) -> Option<Snapped> {
+        self.snap_between(point, f64::NEG_INFINITY, f64::INFINITY)
+    }
+
+    /// Snap `point` onto the nearest part of the line between `from` and `to` metres along it, so
+    /// that a line which passes the same place more than once, such as a loop, is snapped onto the
+    /// expected pass.
+    pub fn snap_between(&self, point: Point, from: f64, to: f64) -> Option<Snapped> {
+        if let [only] = self.points.as_slice() {
+            return Some(Snapped {
+                along: 0.0,
+                offset: only.distance(point),
+            });
+        }
+        self.points
+            .windows(2)
+            .zip(self.distances.windows(2))
+            .filter(|(_, distances)| distances[1] >= from && distances[0] <= to)
+            .map(|(segment, distances)| {
+                let (a, b) = (segment[0], segment[1]);
+                let (bx, by) = a.offset(b);
+                let (px, py) = a.offset(point);
+                let length = bx.mul_add(bx, by * by);
+                let span = distances[1] - distances[0];
+                let (start, end) = if span > 0.0 {
+                    (
+                        ((from - distances[0]) / span).clamp(0.0, 1.0),
+                        ((to - distances[0]) / span).clamp(0.0, 1.0),
+                    )
+                } else {
+                    (0.0, 1.0)
+                };
+                let t = if length > 0.0 {
+                    (px.mul_add(bx, py * by) / length).clamp(start, end)
+                } else {
+                    start
+                };
+                let (dx, dy) = (t.mul_add(bx, -px), t.mul_add(by, -py));
+                Snapped {
+                    along: t.mul_add(span, distances[0]),
+                    offset: dx.hypot(dy),
+                }
+            })
+            .min_by(|a, b| a.offset.total_cmp(&b.offset))
+    }
+}
+
+/// Size of a [`Grid`] cell in degrees, about a kilometre north to south.
+const CELL: f64 = 0.01;
+
+/// Spatial index of points bucketed into cells of [`CELL`] degrees, for finding the points near a
+/// location without measuring the distance to every one.
+#[derive(Default)]
+pub struct Grid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl Grid {
+    pub fn new(points: impl IntoIterator<Item = Point>) -> Self {
+        let mut cells = HashMap::<_, Vec<_>>::new();
+        for (index, point) in points.into_iter().enumerate() {
+            cells.entry(Self::cell(point)).or_default().push(index);
+        }
+        Self { cells }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn cell(point: Point) -> (i32, i32) {
+        (
+            (point.latitude / CELL).floor() as i32,
+            (point.longitude / CELL).floor() as i32,
+        )
+    }
+
+    /// Indices of the points that may be within `radius` metres of `centre`. Every such point is
+    /// included, along with some that are slightly further away.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn near(&self, centre: Point, radius: f64) -> impl Iterator<Item = usize> + '_ {
+        let metres_per_degree = EARTH_RADIUS.to_radians();
+        let latitude_cells = (radius / metres_per_degree / CELL).ceil() as i32;
+        let longitude_cells =
+            (radius / (metres_per_degree * centre.latitude.to_radians().cos()).max(1.0) / CELL)
+                .ceil() as i32;
+        let (latitude, longitude) = Self::cell(centre);
+        (latitude - latitude_cells..=latitude + latitude_cells)
+            .flat_map(move |latitude| {
+                (longitude - longitude_cells..=longitude + longitude_cells)
+                    .map(move |longitude| (latitude, longitude))
+            })
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use csv::Reader;
+use itertools::{Either, Itertools};
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize,
+};
+use worker::{Env, Response};
+use zip::ZipArchive;
+
+use crate::{
+    geo::{Line, Point},
+    upstream::{id, Upstream},
+};
+
+/// Name of the API that publishes the timetables.
+const NAME: &str = "Public Transport - Timetables - For Realtime";
+
+/// Timetables that are not published for a single bus contract, by the ID that is given for them
+/// in place of a contract ID, with their path in the API.
+const FEEDS: [(&str, &str); 22] = [
+    ("sydneytrains", "v1/gtfs/schedule/sydneytrains"),
+    ("nswtrains", "v1/gtfs/schedule/nswtrains"),
+    ("metro", "v2/gtfs/schedule/metro"),
+    ("sydneyferries", "v1/gtfs/schedule/ferries/sydneyferries"),
+    ("MFF", "v1/gtfs/schedule/ferries/MFF"),
+    (
+        "lightrail_innerwest",
+        "v1/gtfs/schedule/lightrail/innerwest",
+    ),
+    (
+        "lightrail_cbdandsoutheast",
+        "v1/gtfs/schedule/lightrail/cbdandsoutheast",
+    ),
+    (
+        "lightrail_newcastle",
+        "v1/gtfs/schedule/lightrail/newcastle",
+    ),
+    (
+        "lightrail_parramatta",
+        "v1/gtfs/schedule/lightrail/parramatta",
+    ),
+    (
+        "centralwestandorana",
+        "v1/gtfs/schedule/regionbuses/centralwestandorana",
+    ),
+    (
+        "centralwestandorana2",
+        "v1/gtfs/schedule/regionbuses/centralwestandorana2",
+    ),
+    ("farwest", "v1/gtfs/schedule/regionbuses/farwest"),
+    (
+        "newcastlehunter",
+        "v1/gtfs/schedule/regionbuses/newcastlehunter",
+    ),
+    (
+        "newenglandnorthwest",
+        "v1/gtfs/schedule/regionbuses/newenglandnorthwest",
+    ),
+    ("northcoast", "v1/gtfs/schedule/regionbuses/northcoast"),
+    ("northcoast2", "v1/gtfs/schedule/regionbuses/northcoast2"),
+    ("northcoast3", "v1/gtfs/schedule/regionbuses/northcoast3"),
+    (
+        "riverinamurray",
+        "v1/gtfs/schedule/regionbuses/riverinamurray",
+    ),
+    (
+        "riverinamurray2",
+        "v1/gtfs/schedule/regionbuses/riverinamurray2",
+    ),
+    ("southeast", "v1/gtfs/schedule/regionbuses/southeast"),
+    ("southeast2", "v1/gtfs/schedule/regionbuses/southeast2"),
+    (
+        "sydneysurrounds",
+        "v1/gtfs/schedule/regionbuses/sydneysurrounds",
+    ),
+];
+
+/// GTFS static timetable for a single agency, as published by the Transport Open Data 'Public
+/// Transport - Timetables - For Realtime' API.
+pub struct Schedule(ZipArchive<Cursor<Vec<u8>>>);
+
+impl Schedule {
+    /// Fetch the timetable for `agency`, which comes from the `agency` parameter of a request.
+    pub async fn fetch(env: &Env, agency: &str) -> Result<Self, worker::Result<Response>> {
+        Self::fetch_upstream(env, Self::upstream(agency)?).await
+    }
+
+    /// Request for the timetable of `agency`, which is either one of [`FEEDS`] or the contract ID
+    /// of a Sydney bus agency.
+    pub fn upstream(agency: &str) -> Result<Upstream, worker::Result<Response>> {
+        match FEEDS.iter().find(|(feed, _)| *feed == agency) {
+            Some((_, path)) => Ok(Upstream::new(NAME, path)),
+            None => {
+                Ok(Upstream::new(NAME, "v1/gtfs/schedule/buses").segment(id("agency", agency)?))
+            }
+        }
+    }
+
+    async fn fetch_upstream(
+        env: &Env,
+        upstream: Upstream,
+    ) -> Result<Self, worker::Result<Response>> {
+        let bytes = upstream.bytes(env).await?;
+        match ZipArchive::new(Cursor::new(bytes)) {
+            Ok(archive) => Ok(Self(archive)),
+            Err(error) => Err(Response::error(format!("Error while reading the ZIP archive from the Transport Open Data 'Public Transport - Timetables - For Realtime' API:\n\n{error:#?}"), 500)),
+        }
+    }
+
+    /// Whether the timetable has the file `name`, for files such as `transfers.txt` that GTFS does
+    /// not require.
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.index_for_name(name).is_some()
+    }
+
+    /// Deserialise every record in the file `name`.
+    pub fn read<T: DeserializeOwned>(
+        &mut self,
+        name: &str,
+    ) -> Result<Vec<T>, worker::Result<Response>> {
+        self.read_filtered(name, |_| true)
+    }
+
+    /// Deserialise the records in the file `name` for which `filter` returns `true`. Records are
+    /// decompressed and dropped as they are read, so large files such as `stop_times.txt` are never
+    /// held in full.
+    pub fn read_filtered<T: DeserializeOwned>(
+        &mut self,
+        name: &str,
+        mut filter: impl FnMut(&T) -> bool,
+    ) -> Result<Vec<T>, worker::Result<Response>> {
+        let file = match self.0.by_name(name) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(Response::error(format!("Error while reading the '{name}' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API:\n\n{error:#?}"), 500));
+            }
+        };
+        let mut records = Vec::new();
+        for record in Reader::from_reader(file).into_deserialize::<T>() {
+            match record {
+                Ok(record) => {
+                    if filter(&record) {
+                        records.push(record);
+                    }
+                }
+                Err(error) => {
+                    return Err(Response::error(format!("Error while reading the records from the '{name}' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API:\n\n{error:#?}"), 500));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Read when the services in `service_ids` run from `calendar.txt` and `calendar_dates.txt`,
+    /// keyed by service ID. Services that neither file mentions are left out.
+    pub fn read_services(
+        &mut self,
+        service_ids: &HashSet<&str>,
+    ) -> Result<HashMap<String, Service>, worker::Result<Response>> {
+        let mut calendars: HashMap<_, _> = self
+            .read_filtered::<Calendar>("calendar.txt", |calendar| {
+                service_ids.contains(calendar.service_id.as_str())
+            })?
+            .into_iter()
+            .map(|calendar| (calendar.service_id.clone(), calendar))
+            .collect();
+        let mut dates = self
+            .read_filtered::<CalendarDate>("calendar_dates.txt", |date| {
+                service_ids.contains(date.service_id.as_str())
+            })?
+            .into_iter()
+            .into_group_map_by(|date| date.service_id.clone());
+        Ok(service_ids
+            .iter()
+            .filter_map(|service_id| {
+                let calendar = calendars.remove(*service_id);
+                let dates = dates.remove(*service_id).unwrap_or_default();
+                if calendar.is_none() && dates.is_empty() {
+                    return None;
+                }
+                let (added, removed) = dates
+                    .into_iter()
+                    .sorted_unstable_by_key(|date| date.date)
+                    .partition_map(|date| {
+                        if date.exception_type == ADDED {
+                            Either::Left(date.date)
+                        } else {
+                            Either::Right(date.date)
+                        }
+                    });
+                let service = Service {
+                    days: calendar.as_ref().map_or_else(Vec::new, Calendar::days),
+                    start_date: calendar.as_ref().map(|calendar| calendar.start_date),
+                    end_date: calendar.as_ref().map(|calendar| calendar.end_date),
+                    added,
+                    removed,
+                };
+                Some(((*service_id).to_string(), service))
+            })
+            .collect())
+    }
+
+    /// Read the shapes in `shape_ids` from `shapes.txt` as lines, keyed by shape ID.
+    pub fn read_shapes(
+        &mut self,
+        shape_ids: &HashSet<&str>,
+    ) -> Result<HashMap<String, Line>, worker::Result<Response>> {
+        let points = self.read_filtered::<ShapePoint>("shapes.txt", |point| {
+            shape_ids.contains(point.shape_id.as_str())
+        })?;
+        Ok(points
+            .into_iter()
+            .into_group_map_by(|point| point.shape_id.clone())
+            .into_iter()
+            .map(|(shape_id, points)| {
+                let points = points
+                    .into_iter()
+                    .sorted_unstable_by_key(|point| point.shape_pt_sequence)
+                    .map(|point| Point::new(point.shape_pt_lat, point.shape_pt_lon))
+                    .collect();
+                (shape_id, Line::new(points))
+            })
+            .collect())
+    }
+}
+
+/// A record from `trips.txt`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Trip {
+    pub route_id: String,
+    pub trip_id: String,
+    pub shape_id: Option<String>,
+    pub trip_headsign: Option<String>,
+    pub direction_id: Option<u8>,
+    pub service_id: String,
+}
+
+/// A record from `shapes.txt`.
+#[derive(Deserialize)]
+struct ShapePoint {
+    shape_id: String,
+    shape_pt_lat: f64,
+    shape_pt_lon: f64,
+    shape_pt_sequence: u32,
+}
+
+/// A record from `stop_times.txt`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct StopTime {
+    pub trip_id: String,
+    pub stop_id: String,
+    pub stop_sequence: u32,
+    /// Time of day as `HH:MM:SS`, which can pass `24:00:00` for trips that run past midnight.
+    pub arrival_time: Option<String>,
+    /// Time of day in the same format as [`StopTime::arrival_time`].
+    pub departure_time: Option<String>,
+}
+
+/// A record from `transfers.txt`. Records without route IDs apply to every route.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Transfer {
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub from_route_id: Option<String>,
+    pub to_route_id: Option<String>,
+    /// 0 or empty for a recommended transfer, 1 for a timed transfer, 2 for a transfer that needs
+    /// [`Transfer::min_transfer_time`], and 3 where transferring is not possible.
+    pub transfer_type: Option<u8>,
+    /// Seconds needed to transfer.
+    pub min_transfer_time: Option<u32>,
+}
+
+/// Value of [`Transfer::transfer_type`] where transferring is not possible.
+pub const NOT_POSSIBLE: u8 = 3;
+
+/// Seconds since the start of the service day of a GTFS time of day, written as `HH:MM:SS`.
+pub fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.trim().splitn(3, ':').map(str::parse::<u32>);
+    let (Some(Ok(hours)), Some(Ok(minutes)), Some(Ok(seconds))) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Value of [`CalendarDate::exception_type`] for a date the service runs on although its
+/// [`Calendar`] says it does not.
+const ADDED: u8 = 1;
+
+/// A record from `calendar.txt`.
+#[derive(Deserialize, Clone, Debug)]
+struct Calendar {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    #[serde(deserialize_with = "date")]
+    start_date: NaiveDate,
+    #[serde(deserialize_with = "date")]
+    end_date: NaiveDate,
+}
+
+impl Calendar {
+    fn days(&self) -> Vec<Weekday> {
+        [
+            (self.monday, Weekday::Mon),
+            (self.tuesday, Weekday::Tue),
+            (self.wednesday, Weekday::Wed),
+            (self.thursday, Weekday::Thu),
+            (self.friday, Weekday::Fri),
+            (self.saturday, Weekday::Sat),
+            (self.sunday, Weekday::Sun),
+        ]
+        .into_iter()
+        .filter(|(runs, _)| *runs == 1)
+        .map(|(_, day)| day)
+        .collect()
+    }
+}
+
+/// A record from `calendar_dates.txt`.
+#[derive(Deserialize, Clone, Debug)]
+struct CalendarDate {
+    service_id: String,
+    #[serde(deserialize_with = "date")]
+    date: NaiveDate,
+    exception_type: u8,
+}
+
+/// Deserialise a GTFS date, written as `YYYYMMDD`.
+fn date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDate, D::Error> {
+    let date = String::deserialize(deserializer)?;
+    NaiveDate::parse_from_str(&date, "%Y%m%d").map_err(de::Error::custom)
+}
+
+/// When a service runs: on `days` of the week from `start_date` to `end_date`, and also on the
+/// `added` dates but not the `removed` ones.
+#[derive(Serialize, Clone, Debug)]
+pub struct Service {
+    pub days: Vec<Weekday>,
+    pub start_date: Option<NaiveDate>,
+    pub end_date: Option<NaiveDate>,
+    pub added: Vec<NaiveDate>,
+    pub removed: Vec<NaiveDate>,
+}
+
+impl Service {
+    /// Whether the service runs on `date`, with exceptions taking priority over the days of the
+    /// week.
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        if self.added.contains(&date) {
+            return true;
+        }
+        if self.removed.contains(&date) {
+            return false;
+        }
+        self.start_date.is_some_and(|start_date| start_date <= date)
+            && self.end_date.is_some_and(|end_date| date <= end_date)
+            && self.days.contains(&date.weekday())
+    }
+}
+
+/// A record from `stops.txt`. Fields are renamed to match the rest of the API, and also accept
+/// their new names so that records saved in the [`Index`](crate::index::Index) can be read back.
+/// Optional fields are left out when empty to keep the index small.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Stop {
+    #[serde(rename(deserialize = "stop_id"), alias = "id")]
+    pub id: String,
+    #[serde(rename(deserialize = "stop_name"), alias = "name")]
+    pub name: String,
+    #[serde(rename(deserialize = "stop_lat"), alias = "latitude")]
+    pub latitude: f64,
+    #[serde(rename(deserialize = "stop_lon"), alias = "longitude")]
+    pub longitude: f64,
+    /// 0 or empty for a stop or platform, and 1 for a station that groups platforms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location_type: Option<u8>,
+    /// ID of the station that the platform is part of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_station: Option<String>,
+    /// Stand or platform name shown at the stop, such as `B4`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform_code: Option<String>,
+    /// 1 if some vehicles can be boarded in a wheelchair, 2 if none can, and 0 or empty if unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wheelchair_boarding: Option<u8>,
+    /// Fare zone of the stop.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone_id: Option<String>,
+}
+
+/// Value of [`Stop::location_type`] for a station.
+const STATION: u8 = 1;
+
+impl Stop {
+    pub const fn point(&self) -> Point {
+        Point::new(self.latitude, self.longitude)
+    }
+
+    pub fn is_station(&self) -> bool {
+        self.location_type == Some(STATION)
+    }
+}
+
+/// A record from `agency.txt`, renamed in the same way as [`Stop`].
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Agency {
+    #[serde(rename(deserialize = "agency_id"), alias = "id")]
+    pub id: String,
+    #[serde(rename(deserialize = "agency_name"), alias = "name")]
+    pub name: String,
+    #[serde(rename(deserialize = "agency_url"), alias = "url")]
+    pub url: String,
+    #[serde(rename(deserialize = "agency_phone"), alias = "phone")]
+    pub phone: Option<String>,
+    #[serde(rename(deserialize = "agency_timezone"), alias = "timezone")]
+    pub timezone: String,
+}
+
+/// A record from `routes.txt`, renamed in the same way as [`Stop`].
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Route {
+    #[serde(rename(deserialize = "route_long_name"), alias = "full_name")]
+    pub full_name: String,
+    #[serde(rename(deserialize = "agency_id"), alias = "agency")]
+    pub agency: String,
+    #[serde(rename(deserialize = "route_short_name"), alias = "name")]
+    pub name: String,
+    #[serde(rename(deserialize = "route_id"), alias = "id")]
+    pub id: String,
+    /// GTFS route type, extended with the values TfNSW uses such as 700 for buses and 712 for
+    /// school buses.
+    #[serde(rename(deserialize = "route_type"), alias = "type")]
+    pub r#type: Option<u16>,
+    /// Colour of the route on signage, as a hex colour without the leading `#`.
+    #[serde(rename(deserialize = "route_color"), alias = "color")]
+    pub color: Option<String>,
+    /// Colour of text drawn over [`Route::color`].
+    #[serde(rename(deserialize = "route_text_color"), alias = "text_color")]
+    pub text_color: Option<String>,
+    #[serde(rename(deserialize = "route_desc"), alias = "description")]
+    pub description: Option<String>,
+}
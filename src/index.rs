@@ -0,0 +1,437 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use web_time::Instant;
+use worker::{kv::KvStore, Env, Response};
+
+use crate::{
+    geo::Grid,
+    gtfs::{Agency, Route, Schedule, Stop, StopTime, Trip},
+    search::tokens,
+};
+
+/// Name of the KV namespace binding that holds the index.
+const BINDING: &str = "INDEX";
+
+/// Name of the variable that lists the contract IDs of the agencies to index, separated by commas.
+const AGENCIES: &str = "INDEX_AGENCIES";
+
+/// Key within [`BINDING`] of when each agency was last indexed.
+const BUILT_KEY: &str = "built";
+
+/// How long an agency's part of the index is used before it is rebuilt.
+const REBUILD_AFTER: TimeDelta = TimeDelta::hours(24);
+
+/// How long an isolate keeps using an index it has already loaded before reading it again.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long an isolate keeps using an index that is missing some agencies, which are added by the
+/// cron trigger soon after a deployment.
+const PARTIAL_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+thread_local! {
+    static CACHE: RefCell<Option<(Instant, Rc<Index>)>> = const { RefCell::new(None) };
+}
+
+fn part_key(contract: &str) -> String {
+    format!("agency:{contract}")
+}
+
+fn store(env: &Env) -> Result<KvStore, worker::Result<Response>> {
+    env.kv(BINDING).map_err(|error| {
+        Response::error(
+            format!("Error while opening the index store:\n\n{error:#?}"),
+            500,
+        )
+    })
+}
+
+/// Contract IDs of the agencies to index, from the [`AGENCIES`] variable.
+fn contracts(env: &Env) -> Result<Vec<String>, worker::Result<Response>> {
+    match env.var(AGENCIES) {
+        Ok(contracts) => Ok(contracts
+            .to_string()
+            .split(',')
+            .map(str::trim)
+            .filter(|contract| !contract.is_empty())
+            .map(ToString::to_string)
+            .collect()),
+        Err(error) => Err(Response::error(
+            format!("Error while reading the `{AGENCIES}` variable:\n\n{error:#?}"),
+            500,
+        )),
+    }
+}
+
+/// When each agency was last indexed, by contract ID.
+async fn built(
+    store: &KvStore,
+) -> Result<HashMap<String, DateTime<Utc>>, worker::Result<Response>> {
+    match store.get(BUILT_KEY).json().await {
+        Ok(built) => Ok(built.unwrap_or_default()),
+        Err(error) => Err(Response::error(
+            format!("Error while reading when the index was built:\n\n{error:#?}"),
+            500,
+        )),
+    }
+}
+
+/// Every agency, stop and route in the timetables of the agencies listed in [`AGENCIES`], so that
+/// searches are not limited to a single agency and do not have to download the timetables
+/// themselves. Each agency is indexed and saved separately by [`Index::rebuild`], and the parts are
+/// merged when the index is loaded.
+pub struct Index {
+    pub agencies: Vec<Agency>,
+    pub routes: Vec<Route>,
+    pub stops: Vec<IndexedStop>,
+    /// Every direction of every route, with the destination most of its trips show.
+    pub directions: Vec<Direction>,
+    pub grid: Grid,
+    /// Position in [`Index::routes`] of each route ID.
+    pub route_ids: HashMap<String, usize>,
+    /// Position in [`Index::stops`] of each stop ID.
+    pub stop_ids: HashMap<String, usize>,
+    /// Search words of each stop's name, from [`tokens`].
+    pub stop_names: Vec<Vec<String>>,
+    /// Search words of each route's full name, from [`tokens`].
+    pub route_names: Vec<Vec<String>>,
+    /// Contract ID of the agency whose timetable each route in [`Index::routes`] is from.
+    contracts: Vec<String>,
+}
+
+/// The part of the [`Index`] built from a single agency's timetable.
+#[derive(Serialize, Deserialize)]
+struct Part {
+    agencies: Vec<Agency>,
+    routes: Vec<Route>,
+    stops: Vec<IndexedStop>,
+    directions: Vec<Direction>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IndexedStop {
+    #[serde(flatten)]
+    pub stop: Stop,
+    /// Indices into [`Index::routes`] of the routes that serve the stop.
+    pub routes: Vec<usize>,
+    /// Indices into [`Index::directions`] of the directions of routes that serve the stop.
+    #[serde(default)]
+    pub directions: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Direction {
+    /// Index into [`Index::routes`] of the route.
+    pub route: usize,
+    pub direction_id: Option<u8>,
+    pub headsign: Option<String>,
+}
+
+impl Part {
+    async fn build(env: &Env, contract: &str) -> Result<Self, worker::Result<Response>> {
+        let mut schedule = Schedule::fetch(env, contract).await?;
+        let agencies = schedule.read::<Agency>("agency.txt")?;
+        let routes = schedule.read::<Route>("routes.txt")?;
+        let route_indices: HashMap<_, _> = routes
+            .iter()
+            .enumerate()
+            .map(|(index, route)| (route.id.as_str(), index))
+            .collect();
+        let trips = schedule
+            .read::<Trip>("trips.txt")?
+            .into_iter()
+            .filter_map(|trip| Some((*route_indices.get(trip.route_id.as_str())?, trip)))
+            .collect_vec();
+        // Each direction is named after the destination that most of its trips show.
+        let directions = trips
+            .iter()
+            .into_group_map_by(|(route, trip)| (*route, trip.direction_id))
+            .into_iter()
+            .map(|((route, direction_id), trips)| Direction {
+                route,
+                direction_id,
+                headsign: trips
+                    .into_iter()
+                    .filter_map(|(_, trip)| trip.trip_headsign.as_deref())
+                    .counts()
+                    .into_iter()
+                    .max_by_key(|(headsign, count)| (*count, *headsign))
+                    .map(|(headsign, _)| headsign.to_string()),
+            })
+            .sorted_unstable_by_key(|direction| (direction.route, direction.direction_id))
+            .collect_vec();
+        let direction_indices: HashMap<_, _> = directions
+            .iter()
+            .enumerate()
+            .map(|(index, direction)| ((direction.route, direction.direction_id), index))
+            .collect();
+        let trips: HashMap<_, _> = trips
+            .into_iter()
+            .map(|(route, trip)| {
+                let direction = direction_indices[&(route, trip.direction_id)];
+                (trip.trip_id, direction)
+            })
+            .collect();
+        let stops = schedule.read::<Stop>("stops.txt")?;
+        let stop_indices: HashMap<_, _> = stops
+            .iter()
+            .enumerate()
+            .map(|(index, stop)| (stop.id.as_str(), index))
+            .collect();
+        let mut served = vec![BTreeSet::new(); stops.len()];
+        // Nothing is kept from `stop_times.txt`, which is by far the largest file, other than which
+        // directions of routes serve each stop.
+        schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+            if let (Some(direction), Some(stop)) = (
+                trips.get(&stop_time.trip_id),
+                stop_indices.get(stop_time.stop_id.as_str()),
+            ) {
+                served[*stop].insert(*direction);
+            }
+            false
+        })?;
+        let stops = stops
+            .into_iter()
+            .zip(served)
+            .map(|(stop, served)| IndexedStop {
+                stop,
+                routes: served
+                    .iter()
+                    .map(|direction| directions[*direction].route)
+                    .dedup()
+                    .collect(),
+                directions: served.into_iter().collect(),
+            })
+            .collect();
+        Ok(Self {
+            agencies,
+            routes,
+            stops,
+            directions,
+        })
+    }
+
+    /// Index the agency `contract` and save its part of the index, recording when it was built in
+    /// `built`. The attempt is recorded even if it fails, so that an agency whose timetable cannot
+    /// be indexed is only retried once it is due again rather than holding up the others.
+    async fn rebuild(
+        env: &Env,
+        store: &KvStore,
+        built: &mut HashMap<String, DateTime<Utc>>,
+        contract: &str,
+    ) -> Result<Self, worker::Result<Response>> {
+        built.insert(contract.to_string(), Utc::now());
+        let result = match store.put(BUILT_KEY, &*built) {
+            Ok(put) => put.execute().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            return Err(Response::error(
+                format!("Error while saving when the index was built:\n\n{error:#?}"),
+                500,
+            ));
+        }
+        let part = Self::build(env, contract).await?;
+        let json = match to_string(&part) {
+            Ok(json) => json,
+            Err(error) => {
+                return Err(Response::error(
+                    format!("Error while serializing the index of `{contract}`:\n\n{error:#?}"),
+                    500,
+                ));
+            }
+        };
+        let result = match store.put(&part_key(contract), json.as_str()) {
+            Ok(put) => put.execute().await,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => Ok(part),
+            Err(error) => Err(Response::error(
+                format!("Error while saving the index of `{contract}`:\n\n{error:#?}"),
+                500,
+            )),
+        }
+    }
+}
+
+impl Index {
+    /// Merge the parts of the index of each agency. Stops that more than one agency serves are
+    /// merged into one stop served by the routes of each.
+    fn merge(parts: Vec<(String, Part)>) -> Self {
+        let mut index = Self {
+            agencies: Vec::new(),
+            routes: Vec::new(),
+            stops: Vec::new(),
+            directions: Vec::new(),
+            grid: Grid::default(),
+            route_ids: HashMap::new(),
+            stop_ids: HashMap::new(),
+            stop_names: Vec::new(),
+            route_names: Vec::new(),
+            contracts: Vec::new(),
+        };
+        for (contract, part) in parts {
+            for agency in part.agencies {
+                if !index.agencies.iter().any(|other| other.id == agency.id) {
+                    index.agencies.push(agency);
+                }
+            }
+            let routes = part
+                .routes
+                .into_iter()
+                .map(|route| {
+                    *index.route_ids.entry(route.id.clone()).or_insert_with(|| {
+                        index.routes.push(route);
+                        index.contracts.push(contract.clone());
+                        index.routes.len() - 1
+                    })
+                })
+                .collect_vec();
+            let directions = part
+                .directions
+                .into_iter()
+                .map(|direction| {
+                    index.directions.push(Direction {
+                        route: routes[direction.route],
+                        ..direction
+                    });
+                    index.directions.len() - 1
+                })
+                .collect_vec();
+            for mut stop in part.stops {
+                stop.routes = stop.routes.iter().map(|route| routes[*route]).collect();
+                stop.directions = stop
+                    .directions
+                    .iter()
+                    .map(|direction| directions[*direction])
+                    .collect();
+                match index.stop_ids.get(&stop.stop.id) {
+                    Some(position) => {
+                        let merged = &mut index.stops[*position];
+                        merged.routes.extend(stop.routes);
+                        merged.routes.sort_unstable();
+                        merged.routes.dedup();
+                        merged.directions.extend(stop.directions);
+                    }
+                    None => {
+                        index
+                            .stop_ids
+                            .insert(stop.stop.id.clone(), index.stops.len());
+                        index.stops.push(stop);
+                    }
+                }
+            }
+        }
+        index.grid = Grid::new(index.stops.iter().map(|stop| stop.stop.point()));
+        index.stop_names = index
+            .stops
+            .iter()
+            .map(|stop| tokens(&stop.stop.name))
+            .collect();
+        index.route_names = index
+            .routes
+            .iter()
+            .map(|route| tokens(&route.full_name))
+            .collect();
+        index
+    }
+
+    /// Rebuild the part of the index of the agency that has gone longest without being indexed,
+    /// if it is due. Run from the cron trigger, which only indexes one agency at a time so that
+    /// each run stays within the memory and CPU limits of a Worker.
+    pub async fn rebuild(env: &Env) -> Result<(), worker::Result<Response>> {
+        let store = store(env)?;
+        let mut built = built(&store).await?;
+        let now = Utc::now();
+        let Some(contract) = contracts(env)?
+            .into_iter()
+            .filter(|contract| {
+                built
+                    .get(contract)
+                    .is_none_or(|built_at| now - *built_at >= REBUILD_AFTER)
+            })
+            .min_by_key(|contract| built.get(contract).copied())
+        else {
+            return Ok(());
+        };
+        Part::rebuild(env, &store, &mut built, &contract).await?;
+        Ok(())
+    }
+
+    /// The agency that runs the route `route_id`.
+    pub fn agency_of(&self, route_id: &str) -> Option<&Agency> {
+        let route = &self.routes[*self.route_ids.get(route_id)?];
+        self.agencies
+            .iter()
+            .find(|agency| agency.id == route.agency)
+    }
+
+    /// Contract ID of the agency whose timetable has the route `route_id`, with which the timetable
+    /// can be fetched by [`Schedule::fetch`].
+    pub fn contract_of(&self, route_id: &str) -> Option<&str> {
+        Some(&self.contracts[*self.route_ids.get(route_id)?])
+    }
+
+    /// The directions of the route `route_id`.
+    pub fn directions_of<'a>(&'a self, route_id: &str) -> impl Iterator<Item = &'a Direction> {
+        let route = self.route_ids.get(route_id).copied();
+        self.directions
+            .iter()
+            .filter(move |direction| Some(direction.route) == route)
+    }
+
+    /// Load the most recently built index. If no agency has been indexed yet, such as just after
+    /// the first deployment, the first agency is indexed now rather than waiting for the cron
+    /// trigger.
+    pub async fn load(env: &Env) -> Result<Rc<Self>, worker::Result<Response>> {
+        let cached = CACHE.with_borrow(|cache| {
+            cache
+                .as_ref()
+                .filter(|(expires_at, _)| Instant::now() < *expires_at)
+                .map(|(_, index)| Rc::clone(index))
+        });
+        if let Some(index) = cached {
+            return Ok(index);
+        }
+        let store = store(env)?;
+        let contracts = contracts(env)?;
+        let mut parts = Vec::new();
+        for contract in &contracts {
+            match store.get(&part_key(contract)).json::<Part>().await {
+                Ok(Some(part)) => parts.push((contract.clone(), part)),
+                Ok(None) => {}
+                Err(error) => {
+                    return Err(Response::error(
+                        format!("Error while reading the index of `{contract}`:\n\n{error:#?}"),
+                        500,
+                    ));
+                }
+            }
+        }
+        let complete = parts.len() == contracts.len();
+        if parts.is_empty()
+            && let Some(contract) = contracts.first()
+        {
+            let mut built = built(&store).await?;
+            let part = Part::rebuild(env, &store, &mut built, contract).await?;
+            parts.push((contract.clone(), part));
+        }
+        let index = Rc::new(Self::merge(parts));
+        let ttl = if complete {
+            CACHE_TTL
+        } else {
+            PARTIAL_CACHE_TTL
+        };
+        CACHE.set(Some((Instant::now() + ttl, Rc::clone(&index))));
+        Ok(index)
+    }
+}
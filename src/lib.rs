@@ -2,24 +2,84 @@
 #![warn(clippy::unwrap_used)]
 
 use console_error_panic_hook::set_once;
-use transport_v1::{routes, stops, times as times_v1};
-use transport_v2::times as times_v2;
-use worker::{event, Context, Env, Request, Response, Router};
+use index::Index;
+use transport_v1::{
+    agencies, calendar, departures_ics, eta, journey, nearby, notify, places, record_positions,
+    routes, school_run, search_routes, search_stops, shape, station, stop_routes, stops, subscribe,
+    subscription, times as times_v1, transfers, trip, trip_planner, unsubscribe,
+};
+use transport_v2::{times as times_v2, times_stream};
+use transport_v3::times as times_v3;
+use worker::{
+    console_error, event, Context, Env, Request, Response, Router, ScheduleContext, ScheduledEvent,
+};
 
 mod icon;
 mod transport_v1;
 mod transport_v2;
+mod transport_v3;
 
 mod common;
+mod geo;
+mod gtfs;
+mod index;
+mod raptor;
+mod school;
+mod search;
+mod upstream;
+
+/// Cron trigger for rebuilding the [`Index`] one agency at a time, so that every agency is indexed
+/// within a few hours of a deployment and again each day.
+const REBUILD_INDEX: &str = "*/5 * * * *";
 
 #[event(fetch)]
 async fn fetch(request: Request, env: Env, _context: Context) -> worker::Result<Response> {
     set_once();
     let router = Router::new()
+        .get_async("/v1/transport/agencies", agencies)
         .get_async("/v1/transport/routes", routes)
+        .get_async("/v1/transport/routes/search", search_routes)
         .get_async("/v1/transport/stops", stops)
+        .get_async("/v1/transport/stops/nearby", nearby)
+        .get_async("/v1/transport/stops/search", search_stops)
+        .get_async("/v1/transport/places", places)
+        .get_async("/v1/transport/stops/:id/routes", stop_routes)
+        .get_async("/v1/transport/stations/:id", station)
+        .get_async("/v1/transport/shape", shape)
+        .get_async("/v1/transport/transfers", transfers)
+        .get_async("/v1/transport/journey", journey)
+        .get_async("/v1/transport/school_run", school_run)
+        .get_async("/v1/transport/trip_planner", trip_planner)
+        .get_async("/v1/transport/trips/:trip_id", trip)
+        .get_async("/v1/transport/calendar", calendar)
+        .get_async("/v1/transport/departures.ics", departures_ics)
         .get_async("/v1/transport/times", times_v1)
         .get_async("/v2/transport/times", times_v2)
+        .get_async("/v2/transport/times/stream", times_stream)
+        .get_async("/v3/transport/times", times_v3)
+        .get_async("/v1/transport/eta", eta)
+        .post_async("/v1/transport/subscriptions", subscribe)
+        .get_async("/v1/transport/subscriptions/:id", subscription)
+        .delete_async("/v1/transport/subscriptions/:id", unsubscribe)
+        .get_async("/v1/school/terms", school::terms)
         .get_async("/v1/icon/choose", icon::choose);
     router.run(request, env).await
 }
+
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _context: ScheduleContext) {
+    set_once();
+    let results = if event.cron() == REBUILD_INDEX {
+        school::check_term_dates();
+        vec![Index::rebuild(&env).await]
+    } else {
+        vec![record_positions(&env).await, notify(&env).await]
+    };
+    for result in results {
+        if let Err(Ok(mut response)) = result
+            && let Ok(text) = response.text().await
+        {
+            console_error!("{text}");
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Journey planning with RAPTOR (Delling, Pajor and Werneck, "Round-Based Public Transit
+//! Routing"), which finds the earliest arrival for each number of vehicles ridden by scanning every
+//! route once per round instead of searching a graph.
+
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+
+/// Time that is never reached, for stops that cannot be reached at all.
+const NEVER: u32 = u32::MAX;
+
+/// Seconds allowed to change from one vehicle to another.
+const TRANSFER_TIME: u32 = 60;
+
+/// Trips that stop at exactly the same stops in the same order. RAPTOR calls these routes, but a
+/// GTFS route usually has several of them.
+pub struct Pattern {
+    pub stops: Vec<usize>,
+    /// Trips in the order they leave the first stop, which is also the order they reach every
+    /// other stop as long as no trip overtakes another.
+    pub trips: Vec<PatternTrip>,
+}
+
+pub struct PatternTrip {
+    /// Position of the trip in the caller's own list of trips.
+    pub trip: usize,
+    /// Arrival and departure times at each of the pattern's stops.
+    pub times: Vec<(u32, u32)>,
+}
+
+pub struct Timetable {
+    patterns: Vec<Pattern>,
+    /// Patterns that serve each stop, with the stop's position in the pattern.
+    stop_patterns: Vec<Vec<(usize, usize)>>,
+    /// Stops that can be walked to from each stop, with the time taken.
+    footpaths: Vec<Vec<(usize, u32)>>,
+    /// Pattern of each of the caller's trips.
+    trip_patterns: HashMap<usize, usize>,
+}
+
+/// A part of a [`Journey`], with times in seconds since the start of the service day.
+#[derive(Clone, Copy, Debug)]
+pub enum Leg {
+    /// Walking from where the journey starts to the stop `to`.
+    Access {
+        to: usize,
+        departure: u32,
+        arrival: u32,
+    },
+    /// Riding the caller's trip `trip` from the stop `from` to the stop `to`.
+    Ride {
+        trip: usize,
+        from: usize,
+        to: usize,
+        departure: u32,
+        arrival: u32,
+    },
+    /// Walking between two stops.
+    Walk {
+        from: usize,
+        to: usize,
+        departure: u32,
+        arrival: u32,
+    },
+    /// Walking from the stop `from` to where the journey ends.
+    Egress {
+        from: usize,
+        departure: u32,
+        arrival: u32,
+    },
+}
+
+impl Leg {
+    /// The same leg travelled in the other direction, on a timetable reversed with
+    /// [`Timetable::reversed`] and `horizon`.
+    const fn reversed(self, horizon: u32) -> Self {
+        match self {
+            Self::Access {
+                to,
+                departure,
+                arrival,
+            } => Self::Egress {
+                from: to,
+                departure: horizon - arrival,
+                arrival: horizon - departure,
+            },
+            Self::Ride {
+                trip,
+                from,
+                to,
+                departure,
+                arrival,
+            } => Self::Ride {
+                trip,
+                from: to,
+                to: from,
+                departure: horizon - arrival,
+                arrival: horizon - departure,
+            },
+            Self::Walk {
+                from,
+                to,
+                departure,
+                arrival,
+            } => Self::Walk {
+                from: to,
+                to: from,
+                departure: horizon - arrival,
+                arrival: horizon - departure,
+            },
+            Self::Egress {
+                from,
+                departure,
+                arrival,
+            } => Self::Access {
+                to: from,
+                departure: horizon - arrival,
+                arrival: horizon - departure,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Journey {
+    pub legs: Vec<Leg>,
+}
+
+impl Journey {
+    pub fn departure(&self) -> u32 {
+        self.legs.first().map_or(NEVER, |leg| match *leg {
+            Leg::Access { departure, .. }
+            | Leg::Ride { departure, .. }
+            | Leg::Walk { departure, .. }
+            | Leg::Egress { departure, .. } => departure,
+        })
+    }
+
+    pub fn arrival(&self) -> u32 {
+        self.legs.last().map_or(NEVER, |leg| match *leg {
+            Leg::Access { arrival, .. }
+            | Leg::Ride { arrival, .. }
+            | Leg::Walk { arrival, .. }
+            | Leg::Egress { arrival, .. } => arrival,
+        })
+    }
+
+    /// A journey from `legs` that leaves as late and arrives as early as its rides allow, rather
+    /// than waiting at either end.
+    fn tightened(mut legs: Vec<Leg>) -> Self {
+        if let [Leg::Access {
+            departure, arrival, ..
+        }, next, ..] = legs.as_mut_slice()
+            && let Leg::Ride {
+                departure: next, ..
+            }
+            | Leg::Walk {
+                departure: next, ..
+            } = *next
+        {
+            *departure = next - (*arrival - *departure);
+            *arrival = next;
+        }
+        if let [.., previous, Leg::Egress {
+            departure, arrival, ..
+        }] = legs.as_mut_slice()
+            && let Leg::Ride {
+                arrival: previous, ..
+            }
+            | Leg::Walk {
+                arrival: previous, ..
+            } = *previous
+        {
+            *arrival = previous + (*arrival - *departure);
+            *departure = previous;
+        }
+        Self { legs }
+    }
+
+    /// Number of vehicles ridden.
+    pub fn rides(&self) -> usize {
+        self.legs
+            .iter()
+            .filter(|leg| matches!(leg, Leg::Ride { .. }))
+            .count()
+    }
+
+    /// The same journey travelled in the other direction, on a timetable reversed with
+    /// [`Timetable::reversed`] and `horizon`.
+    pub fn reversed(self, horizon: u32) -> Self {
+        Self {
+            legs: self
+                .legs
+                .into_iter()
+                .rev()
+                .map(|leg| leg.reversed(horizon))
+                .collect(),
+        }
+    }
+}
+
+/// How a stop was reached in a round.
+#[derive(Clone, Copy)]
+enum Label {
+    Access {
+        walk: u32,
+    },
+    Ride {
+        pattern: usize,
+        trip: usize,
+        from: usize,
+    },
+    Walk {
+        from: usize,
+        walk: u32,
+    },
+}
+
+impl Timetable {
+    pub fn new(patterns: Vec<Pattern>, footpaths: Vec<Vec<(usize, u32)>>) -> Self {
+        let mut stop_patterns = vec![Vec::new(); footpaths.len()];
+        let mut trip_patterns = HashMap::new();
+        for (index, pattern) in patterns.iter().enumerate() {
+            for (position, stop) in pattern.stops.iter().enumerate() {
+                stop_patterns[*stop].push((index, position));
+            }
+            for trip in &pattern.trips {
+                trip_patterns.insert(trip.trip, index);
+            }
+        }
+        Self {
+            patterns,
+            stop_patterns,
+            footpaths,
+            trip_patterns,
+        }
+    }
+
+    /// Stops that can be walked to from `stop`, with the time taken.
+    pub fn footpaths(&self, stop: usize) -> &[(usize, u32)] {
+        &self.footpaths[stop]
+    }
+
+    /// Stops that the caller's trip `trip` serves, in order.
+    pub fn trip_stops(&self, trip: usize) -> Option<&[usize]> {
+        self.trip_patterns
+            .get(&trip)
+            .map(|pattern| self.patterns[*pattern].stops.as_slice())
+    }
+
+    /// The timetable with time running backwards from `horizon`, which must be later than every
+    /// time in it. Searching it for the earliest arrival finds the latest departure that arrives by
+    /// a deadline.
+    pub fn reversed(&self, horizon: u32) -> Self {
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|pattern| Pattern {
+                stops: pattern.stops.iter().rev().copied().collect(),
+                trips: pattern
+                    .trips
+                    .iter()
+                    .map(|trip| PatternTrip {
+                        trip: trip.trip,
+                        times: trip
+                            .times
+                            .iter()
+                            .rev()
+                            .map(|(arrival, departure)| (horizon - departure, horizon - arrival))
+                            .collect(),
+                    })
+                    .sorted_unstable_by_key(|trip| {
+                        trip.times.first().map(|(_, departure)| *departure)
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self::new(patterns, self.footpaths.clone())
+    }
+
+    /// Journeys that leave at `departure`, walk to one of the `access` stops, and walk from one of
+    /// the `egress` stops, each given with the time walked. One journey is returned for each number
+    /// of vehicles ridden up to `max_rides` that arrives earlier than any journey with fewer.
+    #[allow(clippy::too_many_lines)]
+    pub fn search(
+        &self,
+        departure: u32,
+        access: &[(usize, u32)],
+        egress: &[(usize, u32)],
+        max_rides: usize,
+    ) -> Vec<Journey> {
+        let stop_count = self.footpaths.len();
+        let mut best = vec![NEVER; stop_count];
+        let mut arrivals = vec![vec![NEVER; stop_count]];
+        let mut labels = vec![vec![None; stop_count]];
+        let mut marked = HashSet::new();
+        for (stop, walk) in access {
+            let arrival = departure + walk;
+            if arrival < best[*stop] {
+                best[*stop] = arrival;
+                arrivals[0][*stop] = arrival;
+                labels[0][*stop] = Some(Label::Access { walk: *walk });
+                marked.insert(*stop);
+            }
+        }
+        for round in 1..=max_rides {
+            let previous = &arrivals[round - 1];
+            let mut current = previous.clone();
+            let mut round_labels = vec![None; stop_count];
+            // Each pattern is scanned once, from the earliest of its stops that improved.
+            let mut queue = HashMap::new();
+            for stop in marked.drain() {
+                for (pattern, position) in &self.stop_patterns[stop] {
+                    queue
+                        .entry(*pattern)
+                        .and_modify(|earliest: &mut usize| *earliest = (*earliest).min(*position))
+                        .or_insert(*position);
+                }
+            }
+            let mut ridden = HashSet::new();
+            for (index, start) in queue {
+                let pattern = &self.patterns[index];
+                let mut boarded: Option<(usize, usize)> = None;
+                for (position, stop) in pattern.stops.iter().enumerate().skip(start) {
+                    if let Some((trip, from)) = boarded {
+                        let arrival = pattern.trips[trip].times[position].0;
+                        if arrival < best[*stop] {
+                            best[*stop] = arrival;
+                            current[*stop] = arrival;
+                            round_labels[*stop] = Some(Label::Ride {
+                                pattern: index,
+                                trip,
+                                from,
+                            });
+                            ridden.insert(*stop);
+                        }
+                    }
+                    let ready = match previous[*stop] {
+                        NEVER => continue,
+                        ready if round > 1 => ready + TRANSFER_TIME,
+                        ready => ready,
+                    };
+                    // Trips are in order, so the first one still to leave is the earliest.
+                    if let Some(trip) = pattern
+                        .trips
+                        .iter()
+                        .position(|trip| trip.times[position].1 >= ready)
+                        && boarded.is_none_or(|(boarded, _)| trip < boarded)
+                    {
+                        boarded = Some((trip, position));
+                    }
+                }
+            }
+            // Walks only continue from stops reached by a vehicle in this round, so every walk
+            // between stops follows a ride rather than another walk.
+            for stop in &ridden {
+                for (other, walk) in &self.footpaths[*stop] {
+                    let arrival = current[*stop] + walk;
+                    if arrival < best[*other] {
+                        best[*other] = arrival;
+                        current[*other] = arrival;
+                        round_labels[*other] = Some(Label::Walk {
+                            from: *stop,
+                            walk: *walk,
+                        });
+                        marked.insert(*other);
+                    }
+                }
+            }
+            marked.extend(ridden);
+            arrivals.push(current);
+            labels.push(round_labels);
+            if marked.is_empty() {
+                break;
+            }
+        }
+        let mut journeys = Vec::new();
+        let mut earliest = NEVER;
+        for round in 1..arrivals.len() {
+            let Some((stop, walk, arrival)) = egress
+                .iter()
+                .filter(|(stop, _)| arrivals[round][*stop] != NEVER)
+                .map(|(stop, walk)| (*stop, *walk, arrivals[round][*stop] + walk))
+                .min_by_key(|(_, _, arrival)| *arrival)
+            else {
+                continue;
+            };
+            if arrival >= earliest {
+                continue;
+            }
+            earliest = arrival;
+            if let Some(mut legs) = self.legs_to(&arrivals, &labels, round, stop, departure) {
+                legs.push(Leg::Egress {
+                    from: stop,
+                    departure: arrival - walk,
+                    arrival,
+                });
+                journeys.push(Journey::tightened(legs));
+            }
+        }
+        journeys
+    }
+
+    /// Follow the labels back from `stop` in `round` to where the journey started.
+    fn legs_to(
+        &self,
+        arrivals: &[Vec<u32>],
+        labels: &[Vec<Option<Label>>],
+        mut round: usize,
+        mut stop: usize,
+        departure: u32,
+    ) -> Option<Vec<Leg>> {
+        let mut legs = Vec::new();
+        loop {
+            // A stop keeps the label from the last round that improved it.
+            let (found, label) = (0..=round)
+                .rev()
+                .find_map(|round| labels[round][stop].map(|label| (round, label)))?;
+            round = found;
+            let arrival = arrivals[round][stop];
+            match label {
+                Label::Access { walk } => {
+                    legs.push(Leg::Access {
+                        to: stop,
+                        departure,
+                        arrival: departure + walk,
+                    });
+                    break;
+                }
+                Label::Walk { from, walk } => {
+                    legs.push(Leg::Walk {
+                        from,
+                        to: stop,
+                        departure: arrival - walk,
+                        arrival,
+                    });
+                    stop = from;
+                }
+                Label::Ride {
+                    pattern: index,
+                    trip,
+                    from,
+                } => {
+                    let pattern = &self.patterns[index];
+                    let from_stop = pattern.stops[from];
+                    legs.push(Leg::Ride {
+                        trip: pattern.trips[trip].trip,
+                        from: from_stop,
+                        to: stop,
+                        departure: pattern.trips[trip].times[from].1,
+                        arrival,
+                    });
+                    stop = from_stop;
+                    round = round.checked_sub(1)?;
+                }
+            }
+        }
+        legs.reverse();
+        Some(legs)
+    }
+}
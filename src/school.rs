@@ -0,0 +1,180 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+use worker::{console_warn, Request, Response, RouteContext};
+
+use crate::common::today;
+
+/// NSW public school terms and public holidays for each year, as published by the NSW Department
+/// of Education. Add each new year to `school_terms.json` once the department publishes it.
+static YEARS: LazyLock<HashMap<i32, Year>> = LazyLock::new(|| {
+    serde_json::from_str(include_str!("school_terms.json"))
+        .expect("the bundled term dates should be valid")
+});
+
+#[derive(Deserialize)]
+struct Year {
+    eastern: Vec<Term>,
+    western: Vec<Term>,
+    public_holidays: Vec<NaiveDate>,
+}
+
+/// First and last days that students attend in a term.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+struct Term {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+/// Western division schools start the year a week later and finish it a week earlier than eastern
+/// division schools.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Division {
+    Eastern,
+    Western,
+}
+
+impl Year {
+    const fn terms(&self, division: Division) -> &Vec<Term> {
+        match division {
+            Division::Eastern => &self.eastern,
+            Division::Western => &self.western,
+        }
+    }
+}
+
+/// Whether students attend school on `date`, or `None` if the term dates for its year are not
+/// known.
+pub fn is_school_day(date: NaiveDate, division: Division) -> Option<bool> {
+    let year = YEARS.get(&date.year())?;
+    Some(
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+            && !year.public_holidays.contains(&date)
+            && year
+                .terms(division)
+                .iter()
+                .any(|term| (term.start..=term.end).contains(&date)),
+    )
+}
+
+/// Warn if the term dates for next year are not bundled yet, so that they are added before
+/// `school_day` filters and the iCalendar feed stop working in January. Run from the cron trigger.
+pub fn check_term_dates() {
+    let next = today().year() + 1;
+    if !YEARS.contains_key(&next) {
+        console_warn!(
+            "The term dates for {next} are not in `school_terms.json`, so school days in {next} will not be known."
+        );
+    }
+}
+
+/// A `school_day` filter from the `school_day` and `division` parameters of a request. It filters
+/// every service by its date alone, not only school buses (route type 712), so that it can be used
+/// to find the services that run around school.
+#[derive(Clone, Copy)]
+pub struct SchoolDayFilter {
+    school_day: bool,
+    division: Division,
+}
+
+impl SchoolDayFilter {
+    /// Read the filter from the parameters of a request, or `None` if it has no `school_day`
+    /// parameter.
+    pub fn from_pairs(
+        pairs: &HashMap<String, String>,
+    ) -> Result<Option<Self>, worker::Result<Response>> {
+        let division = division(pairs)?;
+        match pairs.get("school_day").map(String::as_str) {
+            None => Ok(None),
+            Some("true") => Ok(Some(Self {
+                school_day: true,
+                division,
+            })),
+            Some("false") => Ok(Some(Self {
+                school_day: false,
+                division,
+            })),
+            Some(_) => Err(Response::error(
+                "The `school_day` parameter must be `true` or `false`.",
+                400,
+            )),
+        }
+    }
+
+    /// Keep the `items` whose date, from `date`, passes the filter, along with any whose date is
+    /// not known. If the term dates for the year of an item are not known, the request fails
+    /// instead, rather than every item from that year being silently left out.
+    pub fn retain<T>(
+        self,
+        items: &mut Vec<T>,
+        date: impl Fn(&T) -> Option<NaiveDate>,
+    ) -> Result<(), worker::Result<Response>> {
+        let mut unknown = None;
+        items.retain(|item| {
+            let Some(date) = date(item) else {
+                return true;
+            };
+            if let Some(school_day) = is_school_day(date, self.division) {
+                school_day == self.school_day
+            } else {
+                unknown.get_or_insert(date.year());
+                false
+            }
+        });
+        match unknown {
+            Some(year) => Err(Response::error(
+                format!("The term dates for {year} are not known, so the `school_day` parameter cannot be used for dates in {year}."),
+                400,
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Read the `division` parameter of a request, which defaults to [`Division::Eastern`].
+pub fn division(pairs: &HashMap<String, String>) -> Result<Division, worker::Result<Response>> {
+    match pairs.get("division").map(String::as_str) {
+        None | Some("eastern") => Ok(Division::Eastern),
+        Some("western") => Ok(Division::Western),
+        Some(_) => Err(Response::error(
+            "The `division` parameter must be `eastern` or `western`.",
+            400,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct TermsResult {
+    year: i32,
+    division: Division,
+    terms: Vec<Term>,
+    public_holidays: Vec<NaiveDate>,
+}
+
+pub async fn terms(request: Request, _context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let year = match pairs.get("year").map(|year| year.parse::<i32>()) {
+        None => today().year(),
+        Some(Ok(year)) => year,
+        Some(Err(_)) => return Response::error("The `year` parameter must be a year.", 400),
+    };
+    let division = match division(&pairs) {
+        Ok(division) => division,
+        Err(response) => return response,
+    };
+    let Some(dates) = YEARS.get(&year) else {
+        return Response::error(format!("The term dates for {year} are not known."), 404);
+    };
+    Response::from_json(&TermsResult {
+        year,
+        division,
+        terms: dates.terms(division).clone(),
+        public_holidays: dates.public_holidays.clone(),
+    })
+}
@@ -0,0 +1,168 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Abbreviations used in TfNSW stop and route names, and the words they stand for. Both sides of a
+/// search are expanded, so "Rd" matches "Road" and "Road" matches "Rd", except for a search word
+/// that may still be being typed, as described in [`search_words`].
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("av", "avenue"),
+    ("ave", "avenue"),
+    ("bvd", "boulevard"),
+    ("blvd", "boulevard"),
+    ("cct", "circuit"),
+    ("cl", "close"),
+    ("cres", "crescent"),
+    ("ct", "court"),
+    ("dr", "drive"),
+    ("esp", "esplanade"),
+    ("gr", "grove"),
+    ("hosp", "hospital"),
+    ("hwy", "highway"),
+    ("int", "interchange"),
+    ("ln", "lane"),
+    ("mt", "mount"),
+    ("nth", "north"),
+    ("opp", "opposite"),
+    ("pde", "parade"),
+    ("pl", "place"),
+    ("pt", "point"),
+    ("pwy", "parkway"),
+    ("rd", "road"),
+    ("sq", "square"),
+    ("st", "street"),
+    ("sth", "south"),
+    ("stn", "station"),
+    ("tce", "terrace"),
+    ("twy", "transitway"),
+    ("uni", "university"),
+    ("wy", "way"),
+];
+
+/// Split `text` into lowercase words without accents.
+fn words(text: &str) -> Vec<String> {
+    text.nfd()
+        .filter(|character| !is_combining_mark(*character))
+        .collect::<String>()
+        .to_lowercase()
+        .split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The word that the abbreviation `word` stands for, if it is one.
+fn expand(word: &str) -> Option<&'static str> {
+    ABBREVIATIONS
+        .iter()
+        .find(|(abbreviation, _)| *abbreviation == word)
+        .map(|(_, expanded)| *expanded)
+}
+
+/// Split `text` into lowercase words without accents, with abbreviations expanded.
+pub fn tokens(text: &str) -> Vec<String> {
+    words(text)
+        .into_iter()
+        .map(|word| expand(&word).map_or(word, ToString::to_string))
+        .collect()
+}
+
+/// A word of a search, which matches a word of a name as typed or as the word it abbreviates.
+#[derive(Clone)]
+pub struct SearchWord {
+    pub raw: String,
+    expanded: Option<&'static str>,
+    /// Whether this is the last word of the search and may still be being typed, in which case its
+    /// expansion only matches a word of a name in full.
+    partial: bool,
+}
+
+/// Split a search into words in the same way as [`tokens`]. Abbreviations are only expanded for
+/// complete words, that is every word but the last unless the search ends in a space, so that "st"
+/// still matches "Station" as well as "Street" while it is being typed. The word as typed is always
+/// matched as well.
+pub fn search_words(text: &str) -> Vec<SearchWord> {
+    let complete = text.ends_with(|character: char| !character.is_alphanumeric());
+    let words = words(text);
+    let last = words.len().saturating_sub(1);
+    words
+        .into_iter()
+        .enumerate()
+        .map(|(position, raw)| SearchWord {
+            expanded: expand(&raw),
+            raw,
+            partial: position == last && !complete,
+        })
+        .collect()
+}
+
+/// Number of single-character insertions, deletions and substitutions that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(a != *b))
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// How well the word `query` matches the word `word`, from 0 to 1.
+#[allow(clippy::cast_precision_loss)]
+fn word_score(query: &str, word: &str) -> f64 {
+    if query == word {
+        return 1.0;
+    }
+    // The last word of a search is often only partly typed.
+    if word.starts_with(query) {
+        return 0.5 + 0.4 * query.len() as f64 / word.len() as f64;
+    }
+    let allowed = match query.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    let distance = edit_distance(query, word);
+    if distance <= allowed {
+        0.6 - 0.1 * distance as f64
+    } else {
+        0.0
+    }
+}
+
+/// How well the search word `query` matches the word `word`, from 0 to 1.
+fn search_word_score(query: &SearchWord, word: &str) -> f64 {
+    let expanded = match query.expanded {
+        Some(expanded) if query.partial && expanded == word => 1.0,
+        Some(_) if query.partial => 0.0,
+        Some(expanded) => word_score(expanded, word),
+        None => 0.0,
+    };
+    word_score(&query.raw, word).max(expanded)
+}
+
+/// How well the words of a search match the words of a name, or `None` if any word of the search
+/// matches nothing. Names that match every search word exactly and have few other words score
+/// highest.
+#[allow(clippy::cast_precision_loss)]
+pub fn score(query: &[SearchWord], name: &[String]) -> Option<f64> {
+    if query.is_empty() || name.is_empty() {
+        return None;
+    }
+    let mut total = 0.0;
+    for query in query {
+        let best = name
+            .iter()
+            .map(|word| search_word_score(query, word))
+            .fold(0.0, f64::max);
+        if best == 0.0 {
+            return None;
+        }
+        total += best;
+    }
+    let coverage = query.len().min(name.len()) as f64 / name.len() as f64;
+    Some(total / query.len() as f64 + 0.2 * coverage)
+}
@@ -4,16 +4,56 @@ use std::{
     io::{Cursor, Read},
 };
 
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use csv::Reader;
 use itertools::Itertools;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use serde_json::from_str;
 use tap::Pipe;
 use worker::{Request, Response, RouteContext};
 use zip::ZipArchive;
 
-use crate::common::{add_auth_header, TimesResult};
+use crate::{
+    common::{fetch_departures, timezone, DepartureTime, Mode, TimesResult, TIMEZONE},
+    gtfs::{Schedule, Stop},
+    index::Index,
+    school::SchoolDayFilter,
+    upstream::{self, Upstream},
+};
+
+mod agencies;
+mod calendar;
+mod eta;
+mod ics;
+mod journey;
+mod nearby;
+mod places;
+mod school_run;
+mod search;
+mod shape;
+mod station;
+mod stop_routes;
+mod subscriptions;
+mod transfers;
+mod trip;
+mod trip_planner;
+
+pub use agencies::agencies;
+pub use calendar::calendar;
+pub use eta::{eta, record_positions};
+pub use ics::departures_ics;
+pub use journey::journey;
+pub use nearby::nearby;
+pub use places::places;
+pub use school_run::school_run;
+pub use search::{search_routes, search_stops};
+pub use shape::shape;
+pub use station::station;
+pub use stop_routes::stop_routes;
+pub use subscriptions::{notify, subscribe, subscription, unsubscribe};
+pub use transfers::transfers;
+pub use trip::trip;
+pub use trip_planner::trip_planner;
 
 pub async fn routes(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
     #[derive(Deserialize)]
@@ -32,6 +72,24 @@ pub async fn routes(request: Request, context: RouteContext<()>) -> worker::Resu
         name: String,
         #[serde(rename(deserialize = "gtfs_route_id_out"))]
         id: String,
+        #[serde(skip_deserializing)]
+        agency_name: Option<String>,
+        #[serde(skip_deserializing)]
+        r#type: Option<u16>,
+        #[serde(skip_deserializing)]
+        color: Option<String>,
+        #[serde(skip_deserializing)]
+        text_color: Option<String>,
+        #[serde(skip_deserializing)]
+        description: Option<String>,
+        #[serde(skip_deserializing)]
+        directions: Vec<Direction>,
+    }
+
+    #[derive(Serialize)]
+    struct Direction {
+        direction_id: Option<u8>,
+        headsign: Option<String>,
     }
     let url = request.url()?;
     let mut pairs = url.query_pairs();
@@ -45,38 +103,38 @@ pub async fn routes(request: Request, context: RouteContext<()>) -> worker::Resu
         return Response::error("The query parameter must be named `query`.", 400);
     }
     let route = value.to_string();
-    let Some(add_auth_header) = add_auth_header(&context.env) else {
-        return Response::error("Missing API key.", 500);
-    };
-    let response = match Client::new()
-        .get("https://api.transport.nsw.gov.au/v1/routes")
-        .query(&[("route", route)])
-        .pipe(add_auth_header)
-        .send()
+    let mut result = match Upstream::new("Transport Routes", "v1/routes")
+        .query("route", &route)
+        .json::<RoutesResult>(&context.env)
         .await
     {
-        Ok(response) => response,
-        Err(error) => {
-            return Response::error(
-                format!(
-                "Error while sending a request to the Transport Open Data 'Transport Routes' API:\n\n{error:#?}"
-            ),
-                500,
-            );
-        }
-    };
-    let text = match response.text().await {
-        Ok(text) => text,
-        Err(error) => {
-            return Response::error(format!("Error while reading text from the response from the Transport Open Data 'Transport Routes' API:\n\n{error:#?}"), 500);
-        }
-    };
-    let result = match from_str::<RoutesResult>(&text) {
         Ok(result) => result,
-        Err(error) => {
-            return Response::error(format!("Error while parsing JSON from the response from the Transport Open Data 'Transport Routes' API:\n\n{error:#?}"), 500);
-        }
+        Err(response) => return response,
     };
+    // Agency names and route details come from the index, so routes are still returned without them
+    // if it cannot be loaded.
+    if let Ok(index) = Index::load(&context.env).await {
+        for route in &mut result.routes {
+            route.agency_name = index.agency_of(&route.id).map(|agency| agency.name.clone());
+            if let Some(indexed) = index
+                .route_ids
+                .get(&route.id)
+                .map(|position| &index.routes[*position])
+            {
+                route.r#type = indexed.r#type;
+                route.color.clone_from(&indexed.color);
+                route.text_color.clone_from(&indexed.text_color);
+                route.description.clone_from(&indexed.description);
+            }
+            route.directions = index
+                .directions_of(&route.id)
+                .map(|direction| Direction {
+                    direction_id: direction.direction_id,
+                    headsign: direction.headsign.clone(),
+                })
+                .collect();
+        }
+    }
     Response::from_json(&result.routes)
 }
 
@@ -87,28 +145,19 @@ pub async fn stops(request: Request, context: RouteContext<()>) -> worker::Resul
         .query_pairs()
         .map(|(key, value)| (key.to_string(), value.to_string()))
         .collect();
-    let Some(add_auth_header) = add_auth_header(&context.env) else {
-        return Response::error("Missing API key.", 500);
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
     };
-    let response = match Client::new()
-        .get(format!(
-            "https://api.transport.nsw.gov.au/v1/gtfs/schedule/buses/{}",
-            pairs["agency"]
-        ))
-        .pipe(add_auth_header)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(error) => {
-            return Response::error(format!("Error while sending a request to the Transport Open Data 'Public Transport - Timetables - For Realtime' API:\n\n{error:#?}"), 500);
-        }
+    let Some(route_id) = pairs.get("id") else {
+        return Response::error("Missing `id` parameter.", 400);
+    };
+    let upstream = match Schedule::upstream(agency) {
+        Ok(upstream) => upstream,
+        Err(response) => return response,
     };
-    let bytes = match response.bytes().await {
+    let bytes = match upstream.bytes(&context.env).await {
         Ok(bytes) => bytes,
-        Err(error) => {
-            return Response::error(format!("Error while reading bytes from the Transport Open Data 'Public Transport - Timetables - For Realtime' API:\n\n{error:#?}"), 500);
-        }
+        Err(response) => return response,
     };
     let mut archive = match ZipArchive::new(Cursor::new(bytes)) {
         Ok(archive) => archive,
@@ -144,7 +193,7 @@ pub async fn stops(request: Request, context: RouteContext<()>) -> worker::Resul
     };
     let trip_id = match match trips.into_records().find_map(|record| {
             record
-                .map(|record| (record[0] == pairs["id"]).then(|| record[2].to_string()))
+                .map(|record| (record[0] == *route_id).then(|| record[2].to_string()))
                 .transpose()
         }) {
         Some(result) => {result},
@@ -193,17 +242,6 @@ pub async fn stops(request: Request, context: RouteContext<()>) -> worker::Resul
             DeserializeError(csv::Error),
             RecordReadError(&'static str, MaybeBorrowed<'a, csv::Error>),
         }
-        #[derive(Deserialize, Serialize, Debug)]
-        struct Stop {
-            #[serde(rename(deserialize = "stop_id"))]
-            id: String,
-            #[serde(rename(deserialize = "stop_name"))]
-            name: String,
-            #[serde(rename(deserialize = "stop_lat"))]
-            latitude: f64,
-            #[serde(rename(deserialize = "stop_lon"))]
-            longitude: f64,
-        }
         let stops = match stop_ids
             .map(|stop_id| {
                 let stop_id = match stop_id {
@@ -251,6 +289,13 @@ pub async fn stops(request: Request, context: RouteContext<()>) -> worker::Resul
     }
 }
 
+#[derive(Serialize)]
+struct TimeResult {
+    /// Planned departure in the time zone of the `tz` parameter.
+    planned: DateTime<Tz>,
+    planned_utc: DateTime<Utc>,
+}
+
 pub async fn times(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
     let url = request.url()?;
     let pairs: HashMap<_, _> = url
@@ -260,33 +305,45 @@ pub async fn times(request: Request, context: RouteContext<()>) -> worker::Resul
     let Some(id) = pairs.get("id") else {
         return Response::error("Missing `id` parameter.", 400);
     };
-    let Some(add_auth_header) = add_auth_header(&context.env) else {
-        return Response::error("Missing API key.", 500);
+    let id = match upstream::id("id", id) {
+        Ok(id) => id,
+        Err(response) => return response,
     };
-    let response = match Client::new()
-        .get(format!(
-            "https://api.transport.nsw.gov.au/v1/tp/departure_mon?outputFormat=rapidJSON&coordOutputFormat=EPSG%3A4326&mode=direct&type_dm=stop&name_dm={id}&departureMonitorMacro=true&excludedMeans=checkbox&exclMOT_1=1&exclMOT_2=1&exclMOT_4=1&exclMOT_7=1&exclMOT_9=1&TfNSWDM=true&version=10.2.1.42"
-        ))
-        .pipe(add_auth_header)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(error) => {
-            return Response::error(format!("Error while sending a request to the Transport Open Data 'Trip Planner APIs' API:\n\n{error:#?}"), 500);
-        }
+    let school_day = match SchoolDayFilter::from_pairs(&pairs) {
+        Ok(school_day) => school_day,
+        Err(response) => return response,
     };
-    let text = match response.text().await {
-        Ok(text) => text,
-        Err(error) => {
-            return Response::error(format!("Error while reading text from the response from the Transport Open Data 'Trip Planner APIs' API:\n\n{error:#?}"), 500);
-        }
+    let modes = match Mode::from_pairs(&pairs) {
+        Ok(modes) => modes,
+        Err(response) => return response,
+    };
+    let when = match DepartureTime::from_pairs(&pairs) {
+        Ok(when) => when,
+        Err(response) => return response,
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
     };
-    let result: TimesResult = match from_str(&text) {
+    let result: TimesResult = match fetch_departures(&context.env, id, &modes, when).await {
         Ok(result) => result,
-        Err(error) => {
-            return Response::error(format!("Error while parsing JSON from the response from the Transport Open Data 'Trip Planner APIs' API:\n\n{error:#?}"), 500);
-        }
+        Err(response) => return response,
     };
-    Response::from_json(&result.times)
+    // These times carry no service day, so they are filtered by the Sydney date they fall on.
+    let mut times = result.times;
+    if let Some(school_day) = school_day
+        && let Err(response) = school_day.retain(&mut times, |time| {
+            Some(time.with_timezone(&TIMEZONE).date_naive())
+        })
+    {
+        return response;
+    }
+    let times = times
+        .into_iter()
+        .map(|time| TimeResult {
+            planned: time.with_timezone(&tz),
+            planned_utc: time,
+        })
+        .collect_vec();
+    Response::from_json(&times)
 }
@@ -0,0 +1,11 @@
+use worker::{Request, Response, RouteContext};
+
+use crate::index::Index;
+
+pub async fn agencies(_request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    Response::from_json(&index.agencies)
+}
@@ -0,0 +1,100 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{NaiveDate, TimeDelta};
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    common::{date, today},
+    gtfs::{Schedule, Trip},
+    school::SchoolDayFilter,
+};
+
+/// Number of days returned when no `to` date is given, starting from `from`.
+const DEFAULT_DAYS: i64 = 28;
+
+/// Most days returned by one request.
+const MAXIMUM_DAYS: i64 = 366;
+
+#[derive(Serialize)]
+struct CalendarResult {
+    from: NaiveDate,
+    to: NaiveDate,
+    /// Dates from `from` to `to` on which at least one of the trips runs.
+    dates: Vec<NaiveDate>,
+    runs_today: bool,
+}
+
+pub async fn calendar(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let route_id = pairs.get("route_id");
+    let trip_id = pairs.get("trip_id");
+    if route_id.is_some() == trip_id.is_some() {
+        return Response::error(
+            "Exactly one of the `route_id` and `trip_id` parameters must be given.",
+            400,
+        );
+    }
+    let school_day = match SchoolDayFilter::from_pairs(&pairs) {
+        Ok(school_day) => school_day,
+        Err(response) => return response,
+    };
+    let today = today();
+    let from = match date(&pairs, "from") {
+        Ok(from) => from.unwrap_or(today),
+        Err(response) => return response,
+    };
+    let to = match date(&pairs, "to") {
+        Ok(to) => to.unwrap_or(from + TimeDelta::days(DEFAULT_DAYS - 1)),
+        Err(response) => return response,
+    };
+    if to < from || (to - from).num_days() >= MAXIMUM_DAYS {
+        return Response::error(
+            format!("The `to` date must be on or after the `from` date, and at most {MAXIMUM_DAYS} days are returned."),
+            400,
+        );
+    }
+    let mut schedule = match Schedule::fetch(&context.env, agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let trips = match schedule.read_filtered::<Trip>("trips.txt", |trip| {
+        route_id.is_none_or(|route_id| trip.route_id == *route_id)
+            && trip_id.is_none_or(|trip_id| trip.trip_id == *trip_id)
+    }) {
+        Ok(trips) => trips,
+        Err(response) => return response,
+    };
+    if trips.is_empty() {
+        return Response::error("No trips in the 'trips.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API match the given route or trip ID.", 400);
+    }
+    let service_ids: HashSet<_> = trips.iter().map(|trip| trip.service_id.as_str()).collect();
+    let services = match schedule.read_services(&service_ids) {
+        Ok(services) => services,
+        Err(response) => return response,
+    };
+    let runs_on = |date| services.values().any(|service| service.runs_on(date));
+    let mut dates = from
+        .iter_days()
+        .take_while(|date| *date <= to)
+        .filter(|date| runs_on(*date))
+        .collect();
+    if let Some(school_day) = school_day
+        && let Err(response) = school_day.retain(&mut dates, |date| Some(*date))
+    {
+        return response;
+    }
+    Response::from_json(&CalendarResult {
+        from,
+        to,
+        dates,
+        runs_today: runs_on(today),
+    })
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, TimeDelta};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use worker::{console_error, kv::KvStore, Env, Request, Response, RouteContext};
+
+use crate::{
+    common::{timezone, TRIP_UPDATES, VEHICLE_POSITIONS},
+    geo::{Line, Point, Snapped},
+    gtfs::{Schedule, Stop, StopTime, Trip},
+};
+
+/// Speed assumed for vehicles that are stationary or do not report a speed, in metres per second.
+/// This is roughly the average speed of a Sydney bus including stops.
+const AVERAGE_SPEED: f64 = 5.0;
+
+/// Reported speeds below this, in metres per second, are treated as the vehicle being stopped.
+const MINIMUM_SPEED: f64 = 1.0;
+
+/// Vehicles further than this from their route's shape, in metres, are treated as off-route.
+const MAXIMUM_OFFSET: f64 = 250.0;
+
+/// Name of the KV namespace binding that holds recent vehicle positions.
+const BINDING: &str = "POSITIONS";
+
+/// Key under which the recent positions of every trip are stored.
+const KEY: &str = "positions";
+
+/// How far back positions are kept for each trip, in seconds. A vehicle's speed is averaged over
+/// this window, so that a bus briefly stopped at lights or speeding between stops does not swing
+/// its estimate.
+const HISTORY: i64 = 180;
+
+/// Shortest history, in seconds, that a speed is averaged over. With less, the reported speed is
+/// used instead.
+const MINIMUM_HISTORY: i64 = 30;
+
+/// How long recorded positions are kept, in seconds, so that they expire if the cron trigger stops
+/// recording them.
+const HISTORY_TTL: u64 = 10 * 60;
+
+/// Fastest a vehicle is taken to move along its shape, in metres per second, when limiting where
+/// it can have got to since its last position.
+const MAXIMUM_SPEED: f64 = 30.0;
+
+/// How far a vehicle may seem to move backwards along its shape, in metres, to allow for GPS error.
+const BACKTRACK: f64 = 50.0;
+
+/// Where a vehicle was at a point in time.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct Sample {
+    /// Unix timestamp of the position in seconds.
+    at: i64,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Sample {
+    const fn point(self) -> Point {
+        Point::new(self.latitude, self.longitude)
+    }
+}
+
+#[derive(Serialize)]
+struct Estimate {
+    trip_id: String,
+    vehicle_id: Option<String>,
+    latitude: f64,
+    longitude: f64,
+    distance_m: f64,
+    speed_m_s: f64,
+    estimated_arrival: DateTime<Tz>,
+    predicted_arrival: Option<DateTime<Tz>>,
+}
+
+#[derive(Serialize)]
+struct EtaResult {
+    estimates: Vec<Estimate>,
+    updated_at: Option<DateTime<Tz>>,
+}
+
+fn store(env: &Env) -> Result<KvStore, worker::Result<Response>> {
+    env.kv(BINDING).map_err(|error| {
+        Response::error(
+            format!("Error while opening the positions store:\n\n{error:#?}"),
+            500,
+        )
+    })
+}
+
+/// Recent positions of each trip, by trip ID. Estimates are still given without them, just less
+/// smoothly, so a failure to read them is only logged.
+async fn history(store: &KvStore) -> HashMap<String, Vec<Sample>> {
+    match store.get(KEY).json().await {
+        Ok(history) => history.unwrap_or_default(),
+        Err(error) => {
+            console_error!("Error while reading the positions:\n\n{error:#?}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Record the position of every vehicle, keeping those from the last [`HISTORY`] seconds of each
+/// trip, so that [`eta`] can average speeds over them. Run from the cron trigger, which is the only
+/// writer of the positions.
+pub async fn record_positions(env: &Env) -> Result<(), worker::Result<Response>> {
+    let store = store(env)?;
+    let positions = VEHICLE_POSITIONS.fetch(env).await?;
+    let mut history = history(&store).await;
+    #[allow(clippy::cast_possible_wrap)]
+    let updated_at = positions.header.timestamp.map(|timestamp| timestamp as i64);
+    // Trips that are no longer in the feed are dropped.
+    let mut samples = HashMap::new();
+    for vehicle in positions
+        .entity
+        .iter()
+        .filter_map(|entity| entity.vehicle.as_ref())
+    {
+        let Some(trip_id) = vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_ref()) else {
+            continue;
+        };
+        let Some(position) = vehicle.position else {
+            continue;
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        let Some(at) = vehicle
+            .timestamp
+            .map(|timestamp| timestamp as i64)
+            .or(updated_at)
+        else {
+            continue;
+        };
+        let mut recent = history.remove(trip_id).unwrap_or_default();
+        recent.retain(|sample| (at - HISTORY..at).contains(&sample.at));
+        recent.push(Sample {
+            at,
+            latitude: position.latitude.into(),
+            longitude: position.longitude.into(),
+        });
+        samples.insert(trip_id.clone(), recent);
+    }
+    let result = match store.put(KEY, &samples) {
+        Ok(put) => put.expiration_ttl(HISTORY_TTL).execute().await,
+        Err(error) => Err(error),
+    };
+    result.map_err(|error| {
+        Response::error(
+            format!("Error while saving the positions:\n\n{error:#?}"),
+            500,
+        )
+    })
+}
+
+/// Snap each of `track`, a vehicle's positions in time order, onto `line`, leaving out those
+/// further than [`MAXIMUM_OFFSET`] from it. Only the part of the line that the vehicle can have
+/// reached since its previous position is searched, so that it is not snapped onto another leg of
+/// a route that loops back on itself.
+fn snap_track(line: &Line, track: &[Sample]) -> Vec<(i64, Snapped)> {
+    let mut snapped: Vec<(i64, Snapped)> = Vec::new();
+    for sample in track {
+        #[allow(clippy::cast_precision_loss)]
+        let near_last = snapped.last().and_then(|(at, last)| {
+            let elapsed = (sample.at - at) as f64;
+            line.snap_between(
+                sample.point(),
+                last.along - BACKTRACK,
+                elapsed.mul_add(MAXIMUM_SPEED, last.along + BACKTRACK),
+            )
+        });
+        if let Some(near) = near_last
+            .filter(|near| near.offset <= MAXIMUM_OFFSET)
+            .or_else(|| line.snap(sample.point()))
+            .filter(|near| near.offset <= MAXIMUM_OFFSET)
+        {
+            snapped.push((sample.at, near));
+        }
+    }
+    snapped
+}
+
+#[allow(clippy::too_many_lines)]
+pub async fn eta(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(stop_id) = pairs.get("stop_id") else {
+        return Response::error("Missing `stop_id` parameter.", 400);
+    };
+    let Some(route_id) = pairs.get("route_id") else {
+        return Response::error("Missing `route_id` parameter.", 400);
+    };
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let positions = match VEHICLE_POSITIONS.fetch(&context.env).await {
+        Ok(positions) => positions,
+        Err(response) => return response,
+    };
+    let updates = match TRIP_UPDATES.fetch(&context.env).await {
+        Ok(updates) => updates,
+        Err(response) => return response,
+    };
+    let mut schedule = match Schedule::fetch(&context.env, agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let mut trips: HashMap<_, _> =
+        match schedule.read_filtered::<Trip>("trips.txt", |trip| trip.route_id == *route_id) {
+            Ok(trips) => trips
+                .into_iter()
+                .map(|trip| (trip.trip_id.clone(), trip))
+                .collect(),
+            Err(response) => return response,
+        };
+    // Only trips that call at the stop are estimated, which leaves out the route's other direction.
+    let serving: HashSet<_> = match schedule
+        .read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+            stop_time.stop_id == *stop_id && trips.contains_key(&stop_time.trip_id)
+        }) {
+        Ok(stop_times) => stop_times
+            .into_iter()
+            .map(|stop_time| stop_time.trip_id)
+            .collect(),
+        Err(response) => return response,
+    };
+    if serving.is_empty() {
+        return Response::error("No trips on the given route stop at the given stop.", 400);
+    }
+    trips.retain(|trip_id, _| serving.contains(trip_id));
+    let stop = match schedule
+        .read_filtered::<Stop>("stops.txt", |stop| stop.id == *stop_id)
+        .map(|stops| stops.into_iter().next())
+    {
+        Ok(Some(stop)) => stop.point(),
+        Ok(None) => {
+            return Response::error(format!("No stop in the 'stops.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API matches the stop ID `{stop_id}`."), 400);
+        }
+        Err(response) => return response,
+    };
+    let shape_ids: HashSet<_> = trips
+        .values()
+        .filter_map(|trip| trip.shape_id.as_deref())
+        .collect();
+    let shapes = match schedule.read_shapes(&shape_ids) {
+        Ok(shapes) => shapes,
+        Err(response) => return response,
+    };
+    let predictions: HashMap<_, _> = updates
+        .entity
+        .iter()
+        .filter_map(|entity| entity.trip_update.as_ref())
+        .filter_map(|trip_update| {
+            let trip_id = trip_update.trip.trip_id.as_ref()?;
+            let arrival = trip_update
+                .stop_time_update
+                .iter()
+                .filter(|update| update.stop_id.as_ref() == Some(stop_id))
+                .find_map(|update| update.arrival.and_then(|arrival| arrival.time))
+                .and_then(DateTime::from_timestamp_secs)?;
+            Some((trip_id, arrival))
+        })
+        .collect();
+    #[allow(clippy::cast_possible_wrap)]
+    let updated_at = positions
+        .header
+        .timestamp
+        .map(|timestamp| timestamp as i64)
+        .and_then(DateTime::from_timestamp_secs);
+    let store = match store(&context.env) {
+        Ok(store) => store,
+        Err(response) => return response,
+    };
+    let history = history(&store).await;
+    let mut estimates = Vec::new();
+    for vehicle in positions
+        .entity
+        .iter()
+        .filter_map(|entity| entity.vehicle.as_ref())
+    {
+        let Some(trip_id) = vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_ref()) else {
+            continue;
+        };
+        let Some(position) = vehicle.position else {
+            continue;
+        };
+        let Some(line) = trips
+            .get(trip_id)
+            .and_then(|trip| trip.shape_id.as_ref())
+            .and_then(|shape_id| shapes.get(shape_id))
+        else {
+            continue;
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        let Some(seen_at) = vehicle
+            .timestamp
+            .map(|timestamp| timestamp as i64)
+            .and_then(DateTime::from_timestamp_secs)
+            .or(updated_at)
+        else {
+            continue;
+        };
+        let mut track: Vec<_> = history
+            .get(trip_id)
+            .map(|previous| {
+                previous
+                    .iter()
+                    .filter(|sample| {
+                        (seen_at.timestamp() - HISTORY..seen_at.timestamp()).contains(&sample.at)
+                    })
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let at = Point::new(position.latitude.into(), position.longitude.into());
+        track.push(Sample {
+            at: seen_at.timestamp(),
+            latitude: at.latitude,
+            longitude: at.longitude,
+        });
+        let snapped = snap_track(line, &track);
+        let Some(&(_, snapped_vehicle)) = snapped
+            .last()
+            .filter(|(snapped_at, _)| *snapped_at == seen_at.timestamp())
+        else {
+            continue;
+        };
+        // A vehicle that has already passed the stop is left out, rather than having the stop
+        // snapped onto where the vehicle is.
+        let Some(snapped_stop) = line
+            .snap_between(stop, snapped_vehicle.along - BACKTRACK, f64::INFINITY)
+            .filter(|snapped_stop| {
+                snapped_stop.offset <= MAXIMUM_OFFSET && snapped_stop.along >= snapped_vehicle.along
+            })
+        else {
+            continue;
+        };
+        let distance_m = snapped_stop.along - snapped_vehicle.along;
+        #[allow(clippy::cast_precision_loss)]
+        let speed_m_s = snapped
+            .first()
+            .filter(|(oldest_at, _)| seen_at.timestamp() - oldest_at >= MINIMUM_HISTORY)
+            .map(|(oldest_at, oldest)| {
+                (snapped_vehicle.along - oldest.along) / (seen_at.timestamp() - oldest_at) as f64
+            })
+            .or_else(|| position.speed.map(f64::from))
+            .filter(|speed| *speed >= MINIMUM_SPEED)
+            .unwrap_or(AVERAGE_SPEED);
+        #[allow(clippy::cast_possible_truncation)]
+        let Some(travel) = TimeDelta::try_seconds((distance_m / speed_m_s).round() as i64) else {
+            continue;
+        };
+        estimates.push(Estimate {
+            trip_id: trip_id.clone(),
+            vehicle_id: vehicle
+                .vehicle
+                .as_ref()
+                .and_then(|vehicle| vehicle.id.clone()),
+            latitude: at.latitude,
+            longitude: at.longitude,
+            distance_m,
+            speed_m_s,
+            estimated_arrival: (seen_at + travel).with_timezone(&tz),
+            predicted_arrival: predictions
+                .get(trip_id)
+                .map(|predicted| predicted.with_timezone(&tz)),
+        });
+    }
+    estimates.sort_unstable_by_key(|estimate| estimate.estimated_arrival);
+    Response::from_json(&EtaResult {
+        estimates,
+        updated_at: updated_at.map(|updated_at| updated_at.with_timezone(&tz)),
+    })
+}
@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use itertools::Itertools;
+use worker::{Headers, Request, Response, RouteContext};
+
+use crate::{
+    common::{service_time, today, TIMEZONE},
+    gtfs::{parse_time, Route, Schedule, Stop, StopTime, Trip},
+    school::{division, is_school_day},
+};
+
+/// Number of school days covered when no `days` parameter is given.
+const DEFAULT_DAYS: usize = 10;
+
+/// Most school days covered by one feed.
+const MAXIMUM_DAYS: usize = 50;
+
+/// How often calendar apps are asked to fetch the feed again, so that changes to the timetable
+/// reach them, as an iCalendar duration.
+const REFRESH_INTERVAL: &str = "PT12H";
+
+/// Longest line in an iCalendar file, in bytes, before it has to be folded onto the next line.
+const MAXIMUM_LINE_LENGTH: usize = 75;
+
+/// Format of a UTC date and time in an iCalendar file.
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Escape `text` for use as an iCalendar text value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Add the content line `line` to `ics`, folding it so that no line is longer than
+/// [`MAXIMUM_LINE_LENGTH`] bytes.
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for character in line.chars() {
+        if length + character.len_utf8() > MAXIMUM_LINE_LENGTH {
+            // The space that starts a folded line counts towards its length.
+            ics.push_str("\r\n ");
+            length = 1;
+        }
+        ics.push(character);
+        length += character.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+/// A scheduled departure from the stop.
+struct Departure<'a> {
+    /// Service day that the trip runs on.
+    date: NaiveDate,
+    time: DateTime<Utc>,
+    trip: &'a Trip,
+    stop_time: &'a StopTime,
+}
+
+pub async fn departures_ics(
+    request: Request,
+    context: RouteContext<()>,
+) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let Some(stop_id) = pairs.get("stop_id") else {
+        return Response::error("Missing `stop_id` parameter.", 400);
+    };
+    let Some(route_id) = pairs.get("route_id") else {
+        return Response::error("Missing `route_id` parameter.", 400);
+    };
+    let division = match division(&pairs) {
+        Ok(division) => division,
+        Err(response) => return response,
+    };
+    let days = match pairs.get("days").map(|days| days.parse::<usize>()) {
+        None => DEFAULT_DAYS,
+        Some(Ok(days)) if (1..=MAXIMUM_DAYS).contains(&days) => days,
+        Some(_) => {
+            return Response::error(
+                format!("The `days` parameter must be a number from 1 to {MAXIMUM_DAYS}."),
+                400,
+            );
+        }
+    };
+    // Days are only counted while their term dates are known, so a feed near the end of the last
+    // known year can cover fewer days than asked for.
+    let dates = today()
+        .iter_days()
+        .take_while(|date| is_school_day(*date, division).is_some())
+        .filter(|date| is_school_day(*date, division) == Some(true))
+        .take(days)
+        .collect_vec();
+    if dates.is_empty() {
+        return Response::error(
+            "The term dates after today are not known, so no school days can be covered.",
+            400,
+        );
+    }
+    let mut schedule = match Schedule::fetch(&context.env, agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let trips: HashMap<_, _> =
+        match schedule.read_filtered::<Trip>("trips.txt", |trip| trip.route_id == *route_id) {
+            Ok(trips) => trips
+                .into_iter()
+                .map(|trip| (trip.trip_id.clone(), trip))
+                .collect(),
+            Err(response) => return response,
+        };
+    if trips.is_empty() {
+        return Response::error("No trips in the 'trips.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API match the given route ID.", 400);
+    }
+    let stop_times = match schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+        stop_time.stop_id == *stop_id && trips.contains_key(&stop_time.trip_id)
+    }) {
+        Ok(stop_times) => stop_times,
+        Err(response) => return response,
+    };
+    if stop_times.is_empty() {
+        return Response::error("No trips on the given route stop at the given stop.", 400);
+    }
+    let service_ids: HashSet<_> = stop_times
+        .iter()
+        .map(|stop_time| trips[&stop_time.trip_id].service_id.as_str())
+        .collect();
+    let services = match schedule.read_services(&service_ids) {
+        Ok(services) => services,
+        Err(response) => return response,
+    };
+    let route = match schedule.read_filtered::<Route>("routes.txt", |route| route.id == *route_id) {
+        Ok(routes) => routes.into_iter().next(),
+        Err(response) => return response,
+    };
+    let stop = match schedule.read_filtered::<Stop>("stops.txt", |stop| stop.id == *stop_id) {
+        Ok(stops) => stops.into_iter().next(),
+        Err(response) => return response,
+    };
+    let (trips, services, stop_times) = (&trips, &services, &stop_times);
+    // A school day's departures include trips of the service day before that run past midnight,
+    // and leave out the day's own trips that run into the next day.
+    let departures = dates
+        .iter()
+        .flat_map(|date| {
+            [date.pred_opt(), Some(*date)]
+                .into_iter()
+                .flatten()
+                .flat_map(move |service_date| {
+                    stop_times.iter().filter_map(move |stop_time| {
+                        let trip = &trips[&stop_time.trip_id];
+                        if !services
+                            .get(&trip.service_id)
+                            .is_some_and(|service| service.runs_on(service_date))
+                        {
+                            return None;
+                        }
+                        let seconds = stop_time
+                            .departure_time
+                            .as_deref()
+                            .or(stop_time.arrival_time.as_deref())
+                            .and_then(parse_time)?;
+                        let time = service_time(service_date, seconds)?;
+                        (time.with_timezone(&TIMEZONE).date_naive() == *date).then_some(Departure {
+                            date: service_date,
+                            time,
+                            trip,
+                            stop_time,
+                        })
+                    })
+                })
+        })
+        .sorted_unstable_by_key(|departure| departure.time)
+        .collect_vec();
+    let route_name = route
+        .as_ref()
+        .map_or(route_id.as_str(), |route| &route.name);
+    let stop_name = stop.as_ref().map_or(stop_id.as_str(), |stop| &stop.name);
+    let now = Utc::now().format(DATE_TIME_FORMAT);
+    let mut ics = String::new();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//Subjective//SubjectiveAPI//EN",
+        "CALSCALE:GREGORIAN",
+        "METHOD:PUBLISH",
+        &format!(
+            "X-WR-CALNAME:{}",
+            escape(&format!("{route_name} from {stop_name}"))
+        ),
+        &format!("REFRESH-INTERVAL;VALUE=DURATION:{REFRESH_INTERVAL}"),
+        &format!("X-PUBLISHED-TTL:{REFRESH_INTERVAL}"),
+    ] {
+        push_line(&mut ics, line);
+    }
+    // Say why the feed stops early, so that it is not mistaken for the route having no more
+    // departures.
+    if dates.len() < days
+        && let Some(last) = dates.last()
+    {
+        push_line(
+            &mut ics,
+            &format!(
+                "X-WR-CALDESC:{}",
+                escape(&format!(
+                    "Covers school days up to {}, as later term dates are not known yet.",
+                    last.format("%-d %B %Y")
+                ))
+            ),
+        );
+    }
+    for departure in departures {
+        let Departure {
+            date,
+            time,
+            trip,
+            stop_time,
+        } = departure;
+        let summary = match &trip.trip_headsign {
+            Some(headsign) => format!("{route_name} to {headsign}"),
+            None => route_name.to_string(),
+        };
+        let description = match &route {
+            Some(route) => format!(
+                "Scheduled departure of trip {} on the {}.",
+                trip.trip_id, route.full_name
+            ),
+            None => format!("Scheduled departure of trip {}.", trip.trip_id),
+        };
+        // The trip, service day and stop identify a departure, so that calendar apps update the
+        // same event when its time changes, and drop it when the trip is removed.
+        let uid = format!(
+            "{}-{}-{}@api.subjective.school",
+            trip.trip_id,
+            date.format("%Y%m%d"),
+            stop_time.stop_sequence,
+        );
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(&mut ics, &format!("UID:{}", escape(&uid)));
+        push_line(&mut ics, &format!("DTSTAMP:{now}"));
+        push_line(
+            &mut ics,
+            &format!("DTSTART:{}", time.format(DATE_TIME_FORMAT)),
+        );
+        push_line(&mut ics, &format!("SUMMARY:{}", escape(&summary)));
+        push_line(&mut ics, &format!("LOCATION:{}", escape(stop_name)));
+        if let Some(stop) = &stop {
+            push_line(
+                &mut ics,
+                &format!("GEO:{};{}", stop.latitude, stop.longitude),
+            );
+        }
+        push_line(&mut ics, &format!("DESCRIPTION:{}", escape(&description)));
+        push_line(&mut ics, "TRANSP:TRANSPARENT");
+        push_line(&mut ics, "END:VEVENT");
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/calendar; charset=utf-8")?;
+    headers.set("Content-Disposition", "inline; filename=\"departures.ics\"")?;
+    Ok(Response::ok(ics)?.with_headers(headers))
+}
@@ -0,0 +1,498 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use serde::Serialize;
+use web_time::Instant;
+use worker::{Env, Request, Response, RouteContext};
+
+use crate::{
+    common::{date, service_day_start, service_time, time, timezone, today},
+    geo::{Grid, Point},
+    gtfs::{parse_time, Route, Schedule, Stop, StopTime, Trip},
+    raptor::{Journey, Leg, Pattern, PatternTrip, Timetable},
+};
+
+/// Furthest in metres that a journey walks between two stops.
+const FOOTPATH_RADIUS: f64 = 400.0;
+
+/// Furthest in metres that a journey walks from where it starts or to where it ends.
+const ACCESS_RADIUS: f64 = 800.0;
+
+/// Walking speed in metres per second.
+const WALKING_SPEED: f64 = 1.2;
+
+/// Most vehicles ridden in one journey.
+const MAX_RIDES: usize = 5;
+
+/// Time in seconds after the start of the service day from which an `arrive_by` search runs
+/// backwards. GTFS times of day can pass 24 hours, but not by this much.
+const HORIZON: u32 = 4 * 24 * 60 * 60;
+
+/// Length of a service day in seconds, by which the times of trips from the day before are moved
+/// back.
+const DAY: u32 = 24 * 60 * 60;
+
+/// How long an isolate keeps the planner it last built, so that searches on the same agency and day
+/// do not build its timetable again.
+const CACHE_TTL: Duration = Duration::from_secs(15 * 60);
+
+thread_local! {
+    static CACHE: RefCell<Option<(Instant, String, Rc<Planner>)>> = const { RefCell::new(None) };
+}
+
+/// Where a journey starts or ends: either a stop, or a coordinate written as `lat,lon`.
+#[derive(Clone, Copy)]
+pub enum Place {
+    Stop(usize),
+    Point(Point),
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum PlaceResult<'a> {
+    Stop(&'a Stop),
+    Point(Point),
+}
+
+#[derive(Serialize)]
+pub struct JourneyResult<'a> {
+    pub departure: Option<DateTime<Tz>>,
+    pub arrival: Option<DateTime<Tz>>,
+    pub duration_sec: u32,
+    pub transfers: usize,
+    pub legs: Vec<LegResult<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum LegResult<'a> {
+    Walk {
+        from: PlaceResult<'a>,
+        to: PlaceResult<'a>,
+        departure: Option<DateTime<Tz>>,
+        arrival: Option<DateTime<Tz>>,
+        distance_m: f64,
+    },
+    Bus {
+        route: Option<&'a Route>,
+        trip_id: &'a str,
+        headsign: Option<&'a str>,
+        from: &'a Stop,
+        to: &'a Stop,
+        departure: Option<DateTime<Tz>>,
+        arrival: Option<DateTime<Tz>>,
+        /// Realtime delays at `from` and `to`, where they are known.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        departure_delay_sec: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        arrival_delay_sec: Option<i32>,
+    },
+}
+
+/// Seconds taken to walk `distance` metres.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn walk_time(distance: f64) -> u32 {
+    (distance / WALKING_SPEED).ceil() as u32
+}
+
+/// Stops within `radius` metres of `centre`, with the time taken to walk to each.
+fn near<'a>(
+    grid: &'a Grid,
+    stops: &'a [Stop],
+    centre: Point,
+    radius: f64,
+) -> impl Iterator<Item = (usize, u32)> + 'a {
+    grid.near(centre, radius)
+        .map(move |index| (index, centre.distance(stops[index].point())))
+        .filter(move |(_, distance)| *distance <= radius)
+        .map(|(index, distance)| (index, walk_time(distance)))
+}
+
+/// The trips of an agency's timetable that run on one day, over which journeys are planned. Only
+/// one agency's timetable is planned over, so journeys cannot change between agencies.
+pub struct Planner {
+    pub date: NaiveDate,
+    stops: Vec<Stop>,
+    routes: HashMap<String, Route>,
+    trips: Vec<Trip>,
+    /// Service day of each trip in [`Planner::trips`], which is the day before [`Planner::date`]
+    /// for trips that continue past midnight from it.
+    service_dates: Vec<NaiveDate>,
+    grid: Grid,
+    timetable: Timetable,
+    /// The timetable with time running backwards, built the first time it is needed.
+    reversed: OnceCell<Timetable>,
+}
+
+impl Planner {
+    /// Load the planner for `agency` on `date`, reusing the one this isolate last built if it is
+    /// for the same agency and day.
+    pub async fn load(
+        env: &Env,
+        agency: &str,
+        date: NaiveDate,
+    ) -> Result<Rc<Self>, worker::Result<Response>> {
+        let cached = CACHE.with_borrow(|cache| {
+            cache
+                .as_ref()
+                .filter(|(loaded_at, cached_agency, planner)| {
+                    loaded_at.elapsed() < CACHE_TTL
+                        && cached_agency == agency
+                        && planner.date == date
+                })
+                .map(|(_, _, planner)| Rc::clone(planner))
+        });
+        if let Some(planner) = cached {
+            return Ok(planner);
+        }
+        let planner = Rc::new(Self::build(env, agency, date).await?);
+        CACHE.set(Some((
+            Instant::now(),
+            agency.to_string(),
+            Rc::clone(&planner),
+        )));
+        Ok(planner)
+    }
+
+    async fn build(
+        env: &Env,
+        agency: &str,
+        date: NaiveDate,
+    ) -> Result<Self, worker::Result<Response>> {
+        let mut schedule = Schedule::fetch(env, agency).await?;
+        let stops = schedule.read::<Stop>("stops.txt")?;
+        let stop_indices: HashMap<_, _> = stops
+            .iter()
+            .enumerate()
+            .map(|(index, stop)| (stop.id.as_str(), index))
+            .collect();
+        let routes = schedule
+            .read::<Route>("routes.txt")?
+            .into_iter()
+            .map(|route| (route.id.clone(), route))
+            .collect();
+        let trips = schedule.read::<Trip>("trips.txt")?;
+        let service_ids: HashSet<_> = trips.iter().map(|trip| trip.service_id.as_str()).collect();
+        let services = schedule.read_services(&service_ids)?;
+        let runs_on = |trip: &Trip, date| {
+            services
+                .get(&trip.service_id)
+                .is_some_and(|service| service.runs_on(date))
+        };
+        // Trips from the day before are kept as well, with their times moved back a day, so that
+        // the part of them that continues past midnight can be ridden. A trip that runs on both
+        // days is kept once for each.
+        let previous = date.pred_opt();
+        let mut kept = Vec::new();
+        let mut service_dates = Vec::new();
+        let mut instances = HashMap::<_, Vec<_>>::new();
+        for trip in trips {
+            if runs_on(&trip, date) {
+                instances
+                    .entry(trip.trip_id.clone())
+                    .or_default()
+                    .push((kept.len(), 0));
+                kept.push(trip.clone());
+                service_dates.push(date);
+            }
+            if let Some(previous) = previous
+                && runs_on(&trip, previous)
+            {
+                instances
+                    .entry(trip.trip_id.clone())
+                    .or_default()
+                    .push((kept.len(), DAY));
+                kept.push(trip);
+                service_dates.push(previous);
+            }
+        }
+        let trips = kept;
+        // Only the trips running on the day are kept from `stop_times.txt`, in as little memory as
+        // possible.
+        let mut rows = Vec::new();
+        schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+            if let (Some(instances), Some(stop)) = (
+                instances.get(stop_time.trip_id.as_str()),
+                stop_indices.get(stop_time.stop_id.as_str()),
+            ) {
+                let arrival = stop_time.arrival_time.as_deref().and_then(parse_time);
+                let departure = stop_time.departure_time.as_deref().and_then(parse_time);
+                if let Some(arrival) = arrival.or(departure) {
+                    let departure = departure.unwrap_or(arrival);
+                    for (trip, shift) in instances {
+                        if arrival >= *shift {
+                            rows.push((
+                                *trip,
+                                stop_time.stop_sequence,
+                                *stop,
+                                arrival - shift,
+                                departure.saturating_sub(*shift),
+                            ));
+                        }
+                    }
+                }
+            }
+            false
+        })?;
+        rows.sort_unstable_by_key(|(trip, sequence, ..)| (*trip, *sequence));
+        let mut patterns: Vec<Pattern> = Vec::new();
+        let mut pattern_indices = HashMap::new();
+        for (trip, rows) in &rows.into_iter().chunk_by(|(trip, ..)| *trip) {
+            let (pattern_stops, times): (Vec<_>, Vec<_>) = rows
+                .map(|(_, _, stop, arrival, departure)| (stop, (arrival, departure)))
+                .unzip();
+            let index = if let Some(index) = pattern_indices.get(&pattern_stops) {
+                *index
+            } else {
+                pattern_indices.insert(pattern_stops.clone(), patterns.len());
+                patterns.push(Pattern {
+                    stops: pattern_stops,
+                    trips: Vec::new(),
+                });
+                patterns.len() - 1
+            };
+            patterns[index].trips.push(PatternTrip { trip, times });
+        }
+        for pattern in &mut patterns {
+            pattern
+                .trips
+                .sort_unstable_by_key(|trip| trip.times.first().map(|(_, departure)| *departure));
+        }
+        let grid = Grid::new(stops.iter().map(Stop::point));
+        let footpaths = stops
+            .iter()
+            .enumerate()
+            .map(|(index, stop)| {
+                near(&grid, &stops, stop.point(), FOOTPATH_RADIUS)
+                    .filter(|(other, _)| *other != index)
+                    .collect_vec()
+            })
+            .collect_vec();
+        Ok(Self {
+            date,
+            stops,
+            routes,
+            trips,
+            service_dates,
+            grid,
+            timetable: Timetable::new(patterns, footpaths),
+            reversed: OnceCell::new(),
+        })
+    }
+
+    /// Read a place written as a stop ID or as `lat,lon`.
+    pub fn place(&self, text: &str) -> Option<Place> {
+        if let Some((latitude, longitude)) = text.split_once(',')
+            && let (Ok(latitude), Ok(longitude)) =
+                (latitude.trim().parse(), longitude.trim().parse())
+        {
+            return Some(Place::Point(Point::new(latitude, longitude)));
+        }
+        self.stops
+            .iter()
+            .position(|stop| stop.id == text)
+            .map(Place::Stop)
+    }
+
+    pub fn stop(&self, stop: usize) -> &Stop {
+        &self.stops[stop]
+    }
+
+    pub fn trip(&self, trip: usize) -> &Trip {
+        &self.trips[trip]
+    }
+
+    /// Service day that the trip `trip` runs on.
+    pub fn service_date(&self, trip: usize) -> NaiveDate {
+        self.service_dates[trip]
+    }
+
+    /// Stops that the trip `trip` serves, in order.
+    pub fn trip_stops(&self, trip: usize) -> &[usize] {
+        self.timetable.trip_stops(trip).unwrap_or_default()
+    }
+
+    /// Stops that can be walked to from `place`, with the time taken.
+    fn reach(&self, place: Place) -> Vec<(usize, u32)> {
+        match place {
+            Place::Stop(stop) => self
+                .timetable
+                .footpaths(stop)
+                .iter()
+                .copied()
+                .chain([(stop, 0)])
+                .collect_vec(),
+            Place::Point(point) => {
+                near(&self.grid, &self.stops, point, ACCESS_RADIUS).collect_vec()
+            }
+        }
+    }
+
+    /// Journeys that leave `from` at `time`, in seconds since the start of the service day.
+    pub fn depart_at(&self, from: Place, to: Place, time: u32) -> Vec<Journey> {
+        self.timetable
+            .search(time, &self.reach(from), &self.reach(to), MAX_RIDES)
+    }
+
+    /// Journeys that leave `from` as late as possible while still arriving at `to` by `time`.
+    pub fn arrive_by(&self, from: Place, to: Place, time: u32) -> Vec<Journey> {
+        self.reversed
+            .get_or_init(|| self.timetable.reversed(HORIZON))
+            .search(
+                HORIZON - time.min(HORIZON),
+                &self.reach(to),
+                &self.reach(from),
+                MAX_RIDES,
+            )
+            .into_iter()
+            .map(|journey| journey.reversed(HORIZON))
+            .collect()
+    }
+
+    /// The journey as it is returned, between `from` and `to`, with times in `tz`.
+    pub fn result(&self, journey: &Journey, from: Place, to: Place, tz: Tz) -> JourneyResult<'_> {
+        let point = |place: Place| match place {
+            Place::Stop(stop) => self.stops[stop].point(),
+            Place::Point(point) => point,
+        };
+        let result = |place: Place| match place {
+            Place::Stop(stop) => PlaceResult::Stop(&self.stops[stop]),
+            Place::Point(point) => PlaceResult::Point(point),
+        };
+        let at = |seconds| service_time(self.date, seconds).map(|time| time.with_timezone(&tz));
+        JourneyResult {
+            departure: at(journey.departure()),
+            arrival: at(journey.arrival()),
+            duration_sec: journey.arrival() - journey.departure(),
+            transfers: journey.rides().saturating_sub(1),
+            legs: journey
+                .legs
+                .iter()
+                .filter_map(|leg| {
+                    let (walk_from, walk_to, departure, arrival) = match *leg {
+                        Leg::Ride {
+                            trip,
+                            from,
+                            to,
+                            departure,
+                            arrival,
+                        } => {
+                            let trip = &self.trips[trip];
+                            return Some(LegResult::Bus {
+                                route: self.routes.get(&trip.route_id),
+                                trip_id: &trip.trip_id,
+                                headsign: trip.trip_headsign.as_deref(),
+                                from: &self.stops[from],
+                                to: &self.stops[to],
+                                departure: at(departure),
+                                arrival: at(arrival),
+                                departure_delay_sec: None,
+                                arrival_delay_sec: None,
+                            });
+                        }
+                        Leg::Access {
+                            to,
+                            departure,
+                            arrival,
+                        } => (from, Place::Stop(to), departure, arrival),
+                        Leg::Walk {
+                            from,
+                            to,
+                            departure,
+                            arrival,
+                        } => (Place::Stop(from), Place::Stop(to), departure, arrival),
+                        Leg::Egress {
+                            from,
+                            departure,
+                            arrival,
+                        } => (Place::Stop(from), to, departure, arrival),
+                    };
+                    let distance_m = point(walk_from).distance(point(walk_to));
+                    // Starting or ending at a stop that the journey also boards or leaves at is
+                    // not a walk.
+                    (distance_m > 0.0).then(|| LegResult::Walk {
+                        from: result(walk_from),
+                        to: result(walk_to),
+                        departure: at(departure),
+                        arrival: at(arrival),
+                        distance_m,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+pub async fn journey(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let Some(from) = pairs.get("from") else {
+        return Response::error("Missing `from` parameter.", 400);
+    };
+    let Some(to) = pairs.get("to") else {
+        return Response::error("Missing `to` parameter.", 400);
+    };
+    let arrive_by = match pairs.get("arrive_by").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => {
+            return Response::error("The `arrive_by` parameter must be `true` or `false`.", 400);
+        }
+    };
+    let date = match date(&pairs, "date") {
+        Ok(date) => date.unwrap_or_else(today),
+        Err(response) => return response,
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let Some(start) = service_day_start(date) else {
+        return Response::error(format!("The service day {date} could not be found."), 400);
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let time = match time(&pairs, "time") {
+        Ok(Some(time)) => time,
+        Ok(None) if arrive_by => {
+            return Response::error(
+                "Missing `time` parameter, which is needed when `arrive_by` is `true`.",
+                400,
+            );
+        }
+        Ok(None) => (Utc::now() - start).num_seconds().clamp(0, HORIZON.into()) as u32,
+        Err(response) => return response,
+    };
+    let planner = match Planner::load(&context.env, agency, date).await {
+        Ok(planner) => planner,
+        Err(response) => return response,
+    };
+    let (Some(from), Some(to)) = (planner.place(from), planner.place(to)) else {
+        return Response::error(
+            "The `from` and `to` parameters must each be a stop ID from the agency or a coordinate written as `lat,lon`.",
+            400,
+        );
+    };
+    let journeys = if arrive_by {
+        planner.arrive_by(from, to, time)
+    } else {
+        planner.depart_at(from, to, time)
+    };
+    let journeys = journeys
+        .iter()
+        .map(|journey| planner.result(journey, from, to, tz))
+        .collect_vec();
+    Response::from_json(&journeys)
+}
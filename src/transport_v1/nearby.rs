@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    geo::Point,
+    gtfs::{Route, Stop},
+    index::Index,
+};
+
+/// Search radius in metres when none is given.
+const DEFAULT_RADIUS: f64 = 500.0;
+
+/// Largest search radius in metres.
+const MAXIMUM_RADIUS: f64 = 2000.0;
+
+/// Most stops returned by one search.
+const LIMIT: usize = 50;
+
+#[derive(Serialize)]
+struct NearbyStop<'a> {
+    #[serde(flatten)]
+    stop: &'a Stop,
+    distance_m: f64,
+    routes: Vec<&'a Route>,
+}
+
+pub async fn nearby(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(latitude) = pairs.get("lat") else {
+        return Response::error("Missing `lat` parameter.", 400);
+    };
+    let Some(longitude) = pairs.get("lon") else {
+        return Response::error("Missing `lon` parameter.", 400);
+    };
+    let Ok(latitude) = latitude.parse::<f64>() else {
+        return Response::error("The `lat` parameter must be a number.", 400);
+    };
+    let Ok(longitude) = longitude.parse::<f64>() else {
+        return Response::error("The `lon` parameter must be a number.", 400);
+    };
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Response::error(
+            "The `lat` and `lon` parameters must be a valid coordinate.",
+            400,
+        );
+    }
+    let radius = match pairs.get("radius").map(|radius| radius.parse::<f64>()) {
+        None => DEFAULT_RADIUS,
+        Some(Ok(radius)) if (0.0..=MAXIMUM_RADIUS).contains(&radius) => radius,
+        Some(_) => {
+            return Response::error(
+                format!(
+                    "The `radius` parameter must be a number of metres up to {MAXIMUM_RADIUS}."
+                ),
+                400,
+            );
+        }
+    };
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let centre = Point::new(latitude, longitude);
+    let stops = index
+        .grid
+        .near(centre, radius)
+        .map(|position| &index.stops[position])
+        .map(|stop| (stop, centre.distance(stop.stop.point())))
+        .filter(|(_, distance)| *distance <= radius)
+        .sorted_unstable_by(|(_, a), (_, b)| a.total_cmp(b))
+        .take(LIMIT)
+        .map(|(stop, distance_m)| NearbyStop {
+            stop: &stop.stop,
+            distance_m,
+            routes: stop
+                .routes
+                .iter()
+                .map(|route| &index.routes[*route])
+                .collect(),
+        })
+        .collect_vec();
+    Response::from_json(&stops)
+}
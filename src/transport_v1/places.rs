@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, RouteContext};
+
+use crate::{common::Mode, gtfs::Stop, upstream::Upstream};
+
+/// Most places returned by one request.
+const LIMIT: usize = 20;
+
+#[derive(Deserialize)]
+struct StopFinderResult {
+    #[serde(default)]
+    locations: Vec<Location>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    id: String,
+    name: String,
+    #[serde(default)]
+    is_global_id: bool,
+    r#type: Option<String>,
+    coord: Option<(f64, f64)>,
+    match_quality: Option<u32>,
+    #[serde(default)]
+    is_best: bool,
+    #[serde(default)]
+    product_classes: Vec<u16>,
+    parent: Option<Parent>,
+}
+
+#[derive(Deserialize)]
+struct Parent {
+    id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Place {
+    #[serde(flatten)]
+    stop: Stop,
+    /// Kind of place, such as `stop`, `platform`, `street`, `singlehouse` for an address, `poi` or
+    /// `suburb`.
+    r#type: Option<String>,
+    /// The stop's global ID, for places that are stops.
+    global_id: Option<String>,
+    modes: Vec<Mode>,
+    /// How closely the place matches the query, with the best match marked.
+    match_quality: Option<u32>,
+    best: bool,
+}
+
+pub async fn places(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(query) = pairs.get("q") else {
+        return Response::error("Missing `q` parameter.", 400);
+    };
+    if query.trim().is_empty() {
+        return Response::error("The `q` parameter must contain at least one word.", 400);
+    }
+    let result: StopFinderResult = match Upstream::new("Trip Planner APIs", "v1/tp/stop_finder")
+        .queries([
+            ("outputFormat", "rapidJSON"),
+            ("coordOutputFormat", "EPSG:4326"),
+            ("type_sf", "any"),
+            ("name_sf", query.trim()),
+            ("TfNSWSF", "true"),
+            ("version", "10.2.1.42"),
+        ])
+        .json(&context.env)
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    // Places without a coordinate cannot be used as a starting point, so they are left out.
+    let places = result
+        .locations
+        .into_iter()
+        .filter_map(|location| {
+            let (latitude, longitude) = location.coord?;
+            let is_platform = location.r#type.as_deref() == Some("platform");
+            Some(Place {
+                global_id: location.is_global_id.then(|| location.id.clone()),
+                stop: Stop {
+                    id: location.id,
+                    name: location.name,
+                    latitude,
+                    longitude,
+                    location_type: None,
+                    parent_station: location
+                        .parent
+                        .and_then(|parent| parent.id)
+                        .filter(|_| is_platform),
+                    platform_code: None,
+                    wheelchair_boarding: None,
+                    zone_id: None,
+                },
+                r#type: location.r#type,
+                modes: location
+                    .product_classes
+                    .into_iter()
+                    .map(|class| Mode::from_class(Some(class)))
+                    .dedup()
+                    .collect(),
+                match_quality: location.match_quality,
+                best: location.is_best,
+            })
+        })
+        .sorted_by(|a, b| b.match_quality.cmp(&a.match_quality))
+        .take(LIMIT)
+        .collect_vec();
+    Response::from_json(&places)
+}
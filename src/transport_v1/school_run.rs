@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use super::journey::{JourneyResult, LegResult, Planner};
+use crate::{
+    common::{
+        date, realtime::TripUpdate, service_day_start, stop_delays, time, timezone, today,
+        TRIP_UPDATES,
+    },
+    raptor::{Journey, Leg},
+};
+
+/// Number of journeys returned when no `count` is given.
+const DEFAULT_COUNT: usize = 3;
+
+/// Most journeys returned by one request.
+const MAXIMUM_COUNT: usize = 10;
+
+/// Most searches run to find enough journeys, each arriving earlier than the one before.
+const MAX_SEARCHES: usize = 8;
+
+#[derive(Serialize)]
+struct SchoolRunResult<'a> {
+    deadline: DateTime<Tz>,
+    /// Journeys that are expected to arrive by the deadline, latest to leave first.
+    journeys: Vec<OnTimeJourney<'a>>,
+    updated_at: Option<DateTime<Tz>>,
+}
+
+#[derive(Serialize)]
+struct OnTimeJourney<'a> {
+    #[serde(flatten)]
+    journey: JourneyResult<'a>,
+    /// When to leave and when the journey arrives once realtime delays are taken into account.
+    expected_departure: DateTime<Tz>,
+    expected_arrival: DateTime<Tz>,
+    /// Seconds between the expected arrival and the deadline.
+    spare_sec: i64,
+}
+
+/// A journey with realtime delays applied, in seconds since the start of the service day.
+struct Expected {
+    departure: i64,
+    arrival: i64,
+    /// Delays leaving and arriving for each vehicle ridden.
+    delays: Vec<(Option<i32>, Option<i32>)>,
+}
+
+/// The journey with realtime delays applied, or `None` if a delay means that one of its
+/// connections would be missed or one of its stops is skipped.
+fn expect(
+    planner: &Planner,
+    updates: &HashMap<(&str, NaiveDate), &TripUpdate>,
+    journey: &Journey,
+) -> Option<Expected> {
+    // Walking before the first vehicle is counted back from when it is expected to leave.
+    let mut walked = 0;
+    let mut departure = None;
+    let mut ready: Option<i64> = None;
+    let mut delays = Vec::new();
+    for leg in &journey.legs {
+        match *leg {
+            Leg::Ride {
+                trip,
+                from,
+                to,
+                departure: scheduled_departure,
+                arrival: scheduled_arrival,
+            } => {
+                let stops = planner.trip_stops(trip);
+                let boarding = stops
+                    .iter()
+                    .position(|stop| *stop == from)
+                    .unwrap_or_default();
+                let alighting = stops[boarding..]
+                    .iter()
+                    .position(|stop| *stop == to)
+                    .map_or(boarding, |position| boarding + position);
+                let (boarding, alighting) = updates
+                    .get(&(
+                        planner.trip(trip).trip_id.as_str(),
+                        planner.service_date(trip),
+                    ))
+                    .map(|update| {
+                        let stops = stops
+                            .iter()
+                            .map(|stop| (None, planner.stop(*stop).id.as_str()))
+                            .collect_vec();
+                        let stop_delays = stop_delays(update, &stops);
+                        (stop_delays[boarding], stop_delays[alighting])
+                    })
+                    .unwrap_or_default();
+                if boarding.skipped || alighting.skipped {
+                    return None;
+                }
+                let leaves = i64::from(scheduled_departure)
+                    + i64::from(boarding.departure.unwrap_or_default());
+                if ready.is_some_and(|ready| leaves < ready) {
+                    return None;
+                }
+                departure.get_or_insert(leaves - walked);
+                ready = Some(
+                    i64::from(scheduled_arrival) + i64::from(alighting.arrival.unwrap_or_default()),
+                );
+                delays.push((boarding.departure, alighting.arrival));
+            }
+            Leg::Access {
+                departure, arrival, ..
+            }
+            | Leg::Walk {
+                departure, arrival, ..
+            }
+            | Leg::Egress {
+                departure, arrival, ..
+            } => {
+                let walk = i64::from(arrival - departure);
+                match &mut ready {
+                    Some(ready) => *ready += walk,
+                    None => walked += walk,
+                }
+            }
+        }
+    }
+    Some(Expected {
+        departure: departure.unwrap_or_else(|| journey.departure().into()),
+        arrival: ready.unwrap_or_else(|| journey.arrival().into()),
+        delays,
+    })
+}
+
+#[allow(clippy::too_many_lines)]
+pub async fn school_run(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let Some(from) = pairs.get("from") else {
+        return Response::error("Missing `from` parameter.", 400);
+    };
+    let Some(to) = pairs.get("to") else {
+        return Response::error("Missing `to` parameter.", 400);
+    };
+    let deadline = match time(&pairs, "deadline") {
+        Ok(Some(deadline)) => deadline,
+        Ok(None) => return Response::error("Missing `deadline` parameter.", 400),
+        Err(response) => return response,
+    };
+    let count = match pairs.get("count").map(|count| count.parse::<usize>()) {
+        None => DEFAULT_COUNT,
+        Some(Ok(count)) if (1..=MAXIMUM_COUNT).contains(&count) => count,
+        Some(_) => {
+            return Response::error(
+                format!("The `count` parameter must be a number from 1 to {MAXIMUM_COUNT}."),
+                400,
+            );
+        }
+    };
+    let date = match date(&pairs, "date") {
+        Ok(date) => date.unwrap_or_else(today),
+        Err(response) => return response,
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let Some(start) = service_day_start(date) else {
+        return Response::error(format!("The service day {date} could not be found."), 400);
+    };
+    let planner = match Planner::load(&context.env, agency, date).await {
+        Ok(planner) => planner,
+        Err(response) => return response,
+    };
+    let (Some(from), Some(to)) = (planner.place(from), planner.place(to)) else {
+        return Response::error(
+            "The `from` and `to` parameters must each be a stop ID from the agency or a coordinate written as `lat,lon`.",
+            400,
+        );
+    };
+    let message = match TRIP_UPDATES.fetch(&context.env).await {
+        Ok(message) => message,
+        Err(response) => return response,
+    };
+    // Trip IDs repeat every service day, so updates are matched by their start date as well, and
+    // those without one are taken to be for today's run of the trip.
+    let updates: HashMap<_, _> = message
+        .entity
+        .iter()
+        .filter_map(|entity| entity.trip_update.as_ref())
+        .filter_map(|update| {
+            let start_date = update
+                .trip
+                .start_date
+                .as_ref()
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+                .unwrap_or_else(today);
+            Some(((update.trip.trip_id.as_deref()?, start_date), update))
+        })
+        .collect();
+    let now = (Utc::now() - start).num_seconds();
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    let mut latest = deadline;
+    // Each search finds the journeys that leave latest while arriving by `latest`, some of which
+    // realtime delays might make late. Searching again just before the latest of them arrives
+    // finds the next ones to leave.
+    for _ in 0..MAX_SEARCHES {
+        let journeys = planner.arrive_by(from, to, latest);
+        let Some(arrival) = journeys.iter().map(Journey::arrival).max() else {
+            break;
+        };
+        let departed = journeys
+            .iter()
+            .all(|journey| i64::from(journey.departure()) < now);
+        for journey in journeys {
+            let rides = journey
+                .legs
+                .iter()
+                .filter_map(|leg| match *leg {
+                    Leg::Ride {
+                        trip, departure, ..
+                    } => Some((trip, departure)),
+                    _ => None,
+                })
+                .collect_vec();
+            if !seen.insert(rides) {
+                continue;
+            }
+            if let Some(expected) = expect(&planner, &updates, &journey)
+                && expected.arrival <= deadline.into()
+                && expected.departure >= now
+            {
+                found.push((journey, expected));
+            }
+        }
+        if found.len() >= count || departed || arrival == 0 {
+            break;
+        }
+        latest = arrival - 1;
+    }
+    let at = |seconds| (start + TimeDelta::seconds(seconds)).with_timezone(&tz);
+    let journeys = found
+        .iter()
+        .sorted_unstable_by_key(|(_, expected)| -expected.departure)
+        .take(count)
+        .map(|(journey, expected)| {
+            let mut result = planner.result(journey, from, to, tz);
+            let buses = result
+                .legs
+                .iter_mut()
+                .filter(|leg| matches!(leg, LegResult::Bus { .. }));
+            for (leg, delays) in buses.zip(&expected.delays) {
+                if let LegResult::Bus {
+                    departure_delay_sec,
+                    arrival_delay_sec,
+                    ..
+                } = leg
+                {
+                    (*departure_delay_sec, *arrival_delay_sec) = *delays;
+                }
+            }
+            OnTimeJourney {
+                journey: result,
+                expected_departure: at(expected.departure),
+                expected_arrival: at(expected.arrival),
+                spare_sec: i64::from(deadline) - expected.arrival,
+            }
+        })
+        .collect();
+    #[allow(clippy::cast_possible_wrap)]
+    let updated_at = message
+        .header
+        .timestamp
+        .map(|timestamp| timestamp as i64)
+        .and_then(DateTime::from_timestamp_secs)
+        .map(|updated_at| updated_at.with_timezone(&tz));
+    Response::from_json(&SchoolRunResult {
+        deadline: at(deadline.into()),
+        journeys,
+        updated_at,
+    })
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    geo::Point,
+    gtfs::{Route, Stop},
+    index::Index,
+    search::{score, search_words, SearchWord},
+};
+
+/// Most results returned by one search.
+const LIMIT: usize = 20;
+
+/// Most that being nearby can add to a stop's score, for a stop at the given coordinate.
+const NEARBY_BONUS: f64 = 0.25;
+
+/// Distance in metres over which the bonus for being nearby falls to about a third.
+const NEARBY_DISTANCE: f64 = 2000.0;
+
+/// How much more a search word matching a route's number counts than one matching a word of its
+/// full name.
+const NUMBER_WEIGHT: f64 = 1.5;
+
+#[derive(Serialize)]
+struct FoundStop<'a> {
+    #[serde(flatten)]
+    stop: &'a Stop,
+    score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance_m: Option<f64>,
+    routes: Vec<&'a Route>,
+}
+
+#[derive(Serialize)]
+struct FoundRoute<'a> {
+    #[serde(flatten)]
+    route: &'a Route,
+    score: f64,
+}
+
+/// How well the search word `query` matches the lowercase route number `number`, from 0 to 1.
+/// Partly typed numbers match, as do numbers without their letter prefix, so "60" matches "601" and
+/// "52" matches "M52".
+#[allow(clippy::cast_precision_loss)]
+fn number_score(query: &str, number: &str) -> f64 {
+    if query == number {
+        1.0
+    } else if number.starts_with(query) {
+        0.5 + 0.4 * query.len() as f64 / number.len() as f64
+    } else if number
+        .trim_start_matches(|character: char| character.is_alphabetic())
+        .starts_with(query)
+        && query.chars().all(|character| character.is_ascii_digit())
+    {
+        0.4
+    } else {
+        0.0
+    }
+}
+
+/// How well a search matches a route, or `None` if it does not. A search word matching the route
+/// number is taken as the number, and every other word must match the route's full name.
+fn route_score(query: &[SearchWord], number: &str, full_name: &[String]) -> Option<f64> {
+    let (numbers, words): (Vec<_>, Vec<_>) = query
+        .iter()
+        .map(|word| (word, number_score(&word.raw, number)))
+        .partition(|(_, score)| *score > 0.0);
+    let number = numbers
+        .into_iter()
+        .map(|(_, score)| score)
+        .fold(0.0, f64::max);
+    let words = words
+        .into_iter()
+        .map(|(word, _)| word.clone())
+        .collect_vec();
+    if words.is_empty() {
+        Some(NUMBER_WEIGHT * number)
+    } else {
+        Some(NUMBER_WEIGHT * number + score(&words, full_name)?)
+    }
+}
+
+pub async fn search_routes(
+    request: Request,
+    context: RouteContext<()>,
+) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(query) = pairs.get("q") else {
+        return Response::error("Missing `q` parameter.", 400);
+    };
+    let query = search_words(query);
+    if query.is_empty() {
+        return Response::error("The `q` parameter must contain at least one word.", 400);
+    }
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let routes = index
+        .routes
+        .iter()
+        .zip(&index.route_names)
+        .filter_map(|(route, full_name)| {
+            let score = route_score(&query, &route.name.to_lowercase(), full_name)?;
+            Some(FoundRoute { route, score })
+        })
+        .sorted_unstable_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.route.name.len().cmp(&b.route.name.len()))
+                .then_with(|| a.route.name.cmp(&b.route.name))
+        })
+        .take(LIMIT)
+        .collect_vec();
+    Response::from_json(&routes)
+}
+
+pub async fn search_stops(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(query) = pairs.get("q") else {
+        return Response::error("Missing `q` parameter.", 400);
+    };
+    let query = search_words(query);
+    if query.is_empty() {
+        return Response::error("The `q` parameter must contain at least one word.", 400);
+    }
+    let centre = match (pairs.get("lat"), pairs.get("lon")) {
+        (None, None) => None,
+        (Some(latitude), Some(longitude)) => {
+            match (latitude.parse::<f64>(), longitude.parse::<f64>()) {
+                (Ok(latitude), Ok(longitude)) => Some(Point::new(latitude, longitude)),
+                _ => {
+                    return Response::error("The `lat` and `lon` parameters must be numbers.", 400);
+                }
+            }
+        }
+        _ => {
+            return Response::error(
+                "The `lat` and `lon` parameters must be given together.",
+                400,
+            );
+        }
+    };
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let stops = index
+        .stops
+        .iter()
+        .zip(&index.stop_names)
+        .filter_map(|(stop, name)| {
+            let score = score(&query, name)?;
+            let distance_m = centre.map(|centre| centre.distance(stop.stop.point()));
+            let bonus = distance_m.map_or(0.0, |distance| {
+                NEARBY_BONUS * (-distance / NEARBY_DISTANCE).exp()
+            });
+            Some((stop, score + bonus, distance_m))
+        })
+        .sorted_unstable_by(|(_, a, _), (_, b, _)| b.total_cmp(a))
+        .take(LIMIT)
+        .map(|(stop, score, distance_m)| FoundStop {
+            stop: &stop.stop,
+            score,
+            distance_m,
+            routes: stop
+                .routes
+                .iter()
+                .map(|route| &index.routes[*route])
+                .collect(),
+        })
+        .collect_vec();
+    Response::from_json(&stops)
+}
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    geo::Point,
+    gtfs::{Schedule, Stop, StopTime, Trip},
+};
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct Feature {
+    geometry: Geometry,
+    properties: Properties,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Geometry {
+    LineString { coordinates: Vec<[f64; 2]> },
+    Point { coordinates: [f64; 2] },
+}
+
+impl From<Point> for [f64; 2] {
+    fn from(point: Point) -> Self {
+        [point.longitude, point.latitude]
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Properties {
+    Shape {
+        shape_id: String,
+        direction_id: Option<u8>,
+        headsign: Option<String>,
+    },
+    Stop {
+        id: String,
+        name: String,
+    },
+}
+
+pub async fn shape(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(id) = pairs.get("id") else {
+        return Response::error("Missing `id` parameter.", 400);
+    };
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let mut schedule = match Schedule::fetch(&context.env, agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let trips = match schedule.read_filtered::<Trip>("trips.txt", |trip| trip.route_id == *id) {
+        Ok(trips) => trips,
+        Err(response) => return response,
+    };
+    if trips.is_empty() {
+        return Response::error(format!("No trips in the 'trips.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API match the route ID `{id}`."), 400);
+    }
+    // Trips in the same direction can take slightly different paths, so each direction is drawn
+    // using the shape that most of its trips follow.
+    let directions = trips
+        .iter()
+        .into_group_map_by(|trip| trip.direction_id)
+        .into_values()
+        .filter_map(|trips| {
+            let shape_id = trips
+                .iter()
+                .filter_map(|trip| trip.shape_id.as_deref())
+                .counts()
+                .into_iter()
+                .max_by_key(|(shape_id, count)| (*count, *shape_id))?
+                .0;
+            trips
+                .into_iter()
+                .find(|trip| trip.shape_id.as_deref() == Some(shape_id))
+        })
+        .sorted_unstable_by_key(|trip| trip.direction_id)
+        .collect_vec();
+    let shape_ids: HashSet<_> = directions
+        .iter()
+        .filter_map(|trip| trip.shape_id.as_deref())
+        .collect();
+    let shapes = match schedule.read_shapes(&shape_ids) {
+        Ok(shapes) => shapes,
+        Err(response) => return response,
+    };
+    let trip_ids: HashSet<_> = directions
+        .iter()
+        .map(|trip| trip.trip_id.as_str())
+        .collect();
+    // Stops are listed in the order they are served, starting with the first direction.
+    let stop_ids = match schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+        trip_ids.contains(stop_time.trip_id.as_str())
+    }) {
+        Ok(stop_times) => stop_times
+            .into_iter()
+            .sorted_unstable_by_key(|stop_time| {
+                let direction = directions
+                    .iter()
+                    .position(|trip| trip.trip_id == stop_time.trip_id);
+                (direction, stop_time.stop_sequence)
+            })
+            .map(|stop_time| stop_time.stop_id)
+            .unique()
+            .collect_vec(),
+        Err(response) => return response,
+    };
+    let wanted: HashSet<_> = stop_ids.iter().collect();
+    let mut stops: HashMap<_, _> =
+        match schedule.read_filtered::<Stop>("stops.txt", |stop| wanted.contains(&stop.id)) {
+            Ok(stops) => stops
+                .into_iter()
+                .map(|stop| (stop.id.clone(), stop))
+                .collect(),
+            Err(response) => return response,
+        };
+    let features = directions
+        .into_iter()
+        .filter_map(|trip| {
+            let shape_id = trip.shape_id.clone()?;
+            let line = shapes.get(&shape_id)?;
+            Some(Feature {
+                geometry: Geometry::LineString {
+                    coordinates: line.points().iter().copied().map(Into::into).collect(),
+                },
+                properties: Properties::Shape {
+                    shape_id,
+                    direction_id: trip.direction_id,
+                    headsign: trip.trip_headsign.clone(),
+                },
+            })
+        })
+        .chain(
+            stop_ids
+                .iter()
+                .filter_map(|stop_id| stops.remove(stop_id))
+                .map(|stop| Feature {
+                    geometry: Geometry::Point {
+                        coordinates: stop.point().into(),
+                    },
+                    properties: Properties::Stop {
+                        id: stop.id,
+                        name: stop.name,
+                    },
+                }),
+        )
+        .collect();
+    Response::from_json(&FeatureCollection { features })
+}
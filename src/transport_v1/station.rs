@@ -0,0 +1,75 @@
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    gtfs::{Route, Stop},
+    index::{Index, IndexedStop},
+};
+
+#[derive(Serialize)]
+struct Station<'a> {
+    #[serde(flatten)]
+    stop: &'a Stop,
+    platforms: Vec<Platform<'a>>,
+}
+
+#[derive(Serialize)]
+struct Platform<'a> {
+    #[serde(flatten)]
+    stop: &'a Stop,
+    routes: Vec<&'a Route>,
+}
+
+pub async fn station(_request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let Some(id) = context.param("id") else {
+        return Response::error("Missing station ID.", 400);
+    };
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let Some(stop) = index
+        .stop_ids
+        .get(id)
+        .map(|position| &index.stops[*position])
+    else {
+        return Response::error(format!("No stop has the ID `{id}`."), 404);
+    };
+    // A platform's ID finds the station it is part of, so either can be given.
+    let station = match &stop.stop.parent_station {
+        Some(parent) if !stop.stop.is_station() => {
+            match index.stop_ids.get(parent).map(|position| &index.stops[*position]) {
+                Some(station) => station,
+                None => return Response::error(format!("The stop `{id}` is part of the station `{parent}`, which is not in the index."), 404),
+            }
+        }
+        _ => stop,
+    };
+    let platforms = index
+        .stops
+        .iter()
+        .filter(|stop| stop.stop.parent_station.as_ref() == Some(&station.stop.id))
+        .sorted_unstable_by(|a, b| {
+            a.stop
+                .platform_code
+                .cmp(&b.stop.platform_code)
+                .then_with(|| a.stop.name.cmp(&b.stop.name))
+        })
+        .map(|stop: &IndexedStop| Platform {
+            stop: &stop.stop,
+            routes: stop
+                .routes
+                .iter()
+                .map(|route| &index.routes[*route])
+                .collect(),
+        })
+        .collect_vec();
+    if !station.stop.is_station() && platforms.is_empty() {
+        return Response::error(format!("The stop `{id}` is not part of a station."), 404);
+    }
+    Response::from_json(&Station {
+        stop: &station.stop,
+        platforms,
+    })
+}
@@ -0,0 +1,66 @@
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    gtfs::{Route, Stop},
+    index::Index,
+};
+
+#[derive(Serialize)]
+struct StopRoutes<'a> {
+    #[serde(flatten)]
+    stop: &'a Stop,
+    routes: Vec<ServingRoute<'a>>,
+}
+
+#[derive(Serialize)]
+struct ServingRoute<'a> {
+    #[serde(flatten)]
+    route: &'a Route,
+    directions: Vec<ServingDirection<'a>>,
+}
+
+#[derive(Serialize)]
+struct ServingDirection<'a> {
+    direction_id: Option<u8>,
+    headsign: Option<&'a str>,
+}
+
+pub async fn stop_routes(_request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let Some(id) = context.param("id") else {
+        return Response::error("Missing stop ID.", 400);
+    };
+    let index = match Index::load(&context.env).await {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let Some(stop) = index
+        .stop_ids
+        .get(id)
+        .map(|position| &index.stops[*position])
+    else {
+        return Response::error(format!("No stop has the ID `{id}`."), 404);
+    };
+    // Directions are stored in route order, so each route's directions are next to each other.
+    let routes = stop
+        .directions
+        .iter()
+        .map(|direction| &index.directions[*direction])
+        .chunk_by(|direction| direction.route)
+        .into_iter()
+        .map(|(route, directions)| ServingRoute {
+            route: &index.routes[route],
+            directions: directions
+                .map(|direction| ServingDirection {
+                    direction_id: direction.direction_id,
+                    headsign: direction.headsign.as_deref(),
+                })
+                .collect(),
+        })
+        .collect_vec();
+    Response::from_json(&StopRoutes {
+        stop: &stop.stop,
+        routes,
+    })
+}
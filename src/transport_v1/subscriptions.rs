@@ -0,0 +1,486 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use http::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use worker::{console_error, kv::KvStore, Env, Request, Response, RouteContext, Url};
+
+use crate::{
+    common::{
+        realtime::vehicle_position::VehicleStopStatus, today, TIMEZONE, TRIP_UPDATES,
+        VEHICLE_POSITIONS,
+    },
+    gtfs::{Schedule, StopTime, Trip},
+    index::Index,
+};
+
+/// Name of the KV namespace binding that holds subscriptions, notification markers and the stops
+/// of subscribed routes' trips.
+const BINDING: &str = "SUBSCRIPTIONS";
+
+/// How long to remember that a trip has already been notified for a subscription, in seconds.
+/// This is a little longer than any single trip, so each run of a trip notifies at most once. The
+/// marker is keyed by the run's start date as well, as trip IDs repeat every service day.
+const NOTIFIED_TTL: u64 = 6 * 60 * 60;
+
+/// How long the stops of a route's trips are kept, in seconds, before they are read from its
+/// timetable again.
+const TRIP_STOPS_TTL: u64 = 24 * 60 * 60;
+
+/// Largest `threshold` a subscription may ask for.
+const MAXIMUM_THRESHOLD: u32 = 20;
+
+/// Host name suffixes that only resolve within a private network, which webhooks may not be sent
+/// to.
+const LOCAL_SUFFIXES: [&str; 3] = [".localhost", ".local", ".internal"];
+
+#[derive(Deserialize, Serialize)]
+struct Subscription {
+    stop_id: String,
+    route_id: String,
+    threshold: u32,
+    callback_url: String,
+    /// IANA time zone in which the webhook is given times, [`TIMEZONE`] if there is none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SubscriptionResult<'a> {
+    id: &'a str,
+    #[serde(flatten)]
+    subscription: &'a Subscription,
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    subscription_id: &'a str,
+    stop_id: &'a str,
+    route_id: &'a str,
+    trip_id: &'a str,
+    vehicle_id: Option<&'a str>,
+    stops_away: u32,
+    predicted_arrival: Option<DateTime<Tz>>,
+}
+
+fn store(env: &Env) -> Result<KvStore, worker::Result<Response>> {
+    env.kv(BINDING).map_err(|error| {
+        Response::error(
+            format!("Error while opening the subscriptions store:\n\n{error:#?}"),
+            500,
+        )
+    })
+}
+
+fn subscription_key(id: &str) -> String {
+    format!("subscription:{id}")
+}
+
+fn notified_key(id: &str, trip_id: &str, start_date: &str) -> String {
+    format!("notified:{id}:{trip_id}:{start_date}")
+}
+
+fn trip_stops_key(route_id: &str) -> String {
+    format!("stops:{route_id}")
+}
+
+/// The stop sequences and stop IDs of each trip on the route `route_id` in order, by trip ID, from
+/// the store or otherwise from the timetable of the route's agency.
+async fn trip_stops(
+    env: &Env,
+    store: &KvStore,
+    route_id: &str,
+) -> Result<HashMap<String, Vec<(u32, String)>>, worker::Result<Response>> {
+    let key = trip_stops_key(route_id);
+    match store.get(&key).json().await {
+        Ok(Some(trip_stops)) => return Ok(trip_stops),
+        Ok(None) => {}
+        Err(error) => {
+            console_error!("Error while reading the trip stops `{key}`:\n\n{error:#?}");
+        }
+    }
+    let index = Index::load(env).await?;
+    let Some(contract) = index.contract_of(route_id) else {
+        return Err(Response::error(
+            format!("The route `{route_id}` is not indexed, so its stops cannot be counted."),
+            500,
+        ));
+    };
+    let mut schedule = Schedule::fetch(env, contract).await?;
+    let trip_ids: HashSet<_> = schedule
+        .read_filtered::<Trip>("trips.txt", |trip| trip.route_id == route_id)?
+        .into_iter()
+        .map(|trip| trip.trip_id)
+        .collect();
+    let mut trip_stops: HashMap<_, Vec<_>> = HashMap::new();
+    for stop_time in schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+        trip_ids.contains(&stop_time.trip_id)
+    })? {
+        trip_stops
+            .entry(stop_time.trip_id)
+            .or_default()
+            .push((stop_time.stop_sequence, stop_time.stop_id));
+    }
+    for stops in trip_stops.values_mut() {
+        stops.sort_unstable();
+    }
+    let result = match store.put(&key, &trip_stops) {
+        Ok(put) => put.expiration_ttl(TRIP_STOPS_TTL).execute().await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        console_error!("Error while saving the trip stops `{key}`:\n\n{error:#?}");
+    }
+    Ok(trip_stops)
+}
+
+/// Generate a random 128-bit subscription ID, so that the ID cannot be guessed by anyone but its
+/// subscriber.
+fn new_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Whether webhooks may be sent to `url`, which must use HTTPS and name a public host rather than
+/// an IP address or a local name.
+fn check_callback_url(url: &Url) -> Result<(), &'static str> {
+    if url.scheme() != "https" {
+        return Err("The `callback_url` must use HTTPS.");
+    }
+    let Some(domain) = url.domain() else {
+        return Err("The `callback_url` must name a host, not an IP address.");
+    };
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    if domain == "localhost"
+        || !domain.contains('.')
+        || LOCAL_SUFFIXES.iter().any(|suffix| domain.ends_with(suffix))
+    {
+        return Err("The `callback_url` must name a public host.");
+    }
+    Ok(())
+}
+
+pub async fn subscribe(
+    mut request: Request,
+    context: RouteContext<()>,
+) -> worker::Result<Response> {
+    let subscription = match request.json::<Subscription>().await {
+        Ok(subscription) => subscription,
+        Err(error) => {
+            return Response::error(
+                format!(
+                    "Error while parsing the subscription from the request body:\n\n{error:#?}"
+                ),
+                400,
+            );
+        }
+    };
+    if subscription
+        .tz
+        .as_ref()
+        .is_some_and(|tz| tz.parse::<Tz>().is_err())
+    {
+        return Response::error(
+            "The `tz` must be an IANA time zone, such as `Australia/Sydney`.",
+            400,
+        );
+    }
+    if !(1..=MAXIMUM_THRESHOLD).contains(&subscription.threshold) {
+        return Response::error(
+            format!("The `threshold` must be between 1 and {MAXIMUM_THRESHOLD}."),
+            400,
+        );
+    }
+    match Url::parse(&subscription.callback_url) {
+        Ok(url) => {
+            if let Err(message) = check_callback_url(&url) {
+                return Response::error(message, 400);
+            }
+        }
+        Err(error) => {
+            return Response::error(
+                format!("The `callback_url` is not a valid URL: {error}."),
+                400,
+            );
+        }
+    }
+    let store = match store(&context.env) {
+        Ok(store) => store,
+        Err(response) => return response,
+    };
+    let id = match new_id() {
+        Ok(id) => id,
+        Err(error) => {
+            return Response::error(
+                format!("Error while generating a subscription ID:\n\n{error:#?}"),
+                500,
+            );
+        }
+    };
+    let result = match store.put(&subscription_key(&id), &subscription) {
+        Ok(put) => put.execute().await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        return Response::error(
+            format!("Error while saving the subscription:\n\n{error:#?}"),
+            500,
+        );
+    }
+    Ok(Response::from_json(&SubscriptionResult {
+        id: &id,
+        subscription: &subscription,
+    })?
+    .with_status(201))
+}
+
+pub async fn subscription(
+    _request: Request,
+    context: RouteContext<()>,
+) -> worker::Result<Response> {
+    let Some(id) = context.param("id") else {
+        return Response::error("Missing subscription ID.", 400);
+    };
+    let store = match store(&context.env) {
+        Ok(store) => store,
+        Err(response) => return response,
+    };
+    match store
+        .get(&subscription_key(id))
+        .json::<Subscription>()
+        .await
+    {
+        Ok(Some(subscription)) => Response::from_json(&SubscriptionResult {
+            id,
+            subscription: &subscription,
+        }),
+        Ok(None) => Response::error("No subscription has the given ID.", 404),
+        Err(error) => Response::error(
+            format!("Error while reading the subscription:\n\n{error:#?}"),
+            500,
+        ),
+    }
+}
+
+pub async fn unsubscribe(_request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let Some(id) = context.param("id") else {
+        return Response::error("Missing subscription ID.", 400);
+    };
+    let store = match store(&context.env) {
+        Ok(store) => store,
+        Err(response) => return response,
+    };
+    match store.delete(&subscription_key(id)).await {
+        Ok(()) => Ok(Response::empty()?.with_status(204)),
+        Err(error) => Response::error(
+            format!("Error while deleting the subscription:\n\n{error:#?}"),
+            500,
+        ),
+    }
+}
+
+/// Check every subscription against the realtime feeds and call the webhook of each one whose
+/// threshold has been reached by a trip that has not already been notified. Run from the cron
+/// trigger.
+#[allow(clippy::too_many_lines)]
+pub async fn notify(env: &Env) -> Result<(), worker::Result<Response>> {
+    let store = store(env)?;
+    let mut subscriptions = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = store.list().prefix("subscription:".to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = match list.execute().await {
+            Ok(page) => page,
+            Err(error) => {
+                return Err(Response::error(
+                    format!("Error while listing the subscriptions:\n\n{error:#?}"),
+                    500,
+                ));
+            }
+        };
+        for key in page.keys {
+            match store.get(&key.name).json::<Subscription>().await {
+                Ok(Some(subscription)) => {
+                    let id = key.name["subscription:".len()..].to_string();
+                    subscriptions.push((id, subscription));
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    console_error!(
+                        "Error while reading the subscription `{}`:\n\n{error:#?}",
+                        key.name
+                    );
+                }
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    if subscriptions.is_empty() {
+        return Ok(());
+    }
+    let positions = VEHICLE_POSITIONS.fetch(env).await?;
+    let updates = TRIP_UPDATES.fetch(env).await?;
+    let vehicles: HashMap<_, _> = positions
+        .entity
+        .iter()
+        .filter_map(|entity| entity.vehicle.as_ref())
+        .filter_map(|vehicle| Some((vehicle.trip.as_ref()?.trip_id.as_ref()?, vehicle)))
+        .collect();
+    let client = Client::new();
+    let mut route_stops = HashMap::new();
+    for (id, subscription) in &subscriptions {
+        let tz = subscription
+            .tz
+            .as_ref()
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or(TIMEZONE);
+        for trip_update in updates
+            .entity
+            .iter()
+            .filter_map(|entity| entity.trip_update.as_ref())
+            .filter(|trip_update| {
+                trip_update.trip.route_id.as_ref() == Some(&subscription.route_id)
+            })
+        {
+            let Some(trip_id) = trip_update.trip.trip_id.as_ref() else {
+                continue;
+            };
+            let Some(vehicle) = vehicles.get(trip_id) else {
+                continue;
+            };
+            // Realtime updates can leave out stops, such as those whose prediction has not
+            // changed, so stops are counted by their position in the trip's timetabled stops.
+            let stops = match route_stops.entry(subscription.route_id.as_str()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    match trip_stops(env, &store, &subscription.route_id).await {
+                        Ok(trip_stops) => entry.insert(trip_stops),
+                        Err(error) => {
+                            if let Ok(mut response) = error
+                                && let Ok(text) = response.text().await
+                            {
+                                console_error!("{text}");
+                            }
+                            entry.insert(HashMap::new())
+                        }
+                    }
+                }
+            };
+            let Some(stops) = stops.get(trip_id) else {
+                continue;
+            };
+            let Some(current) = vehicle
+                .current_stop_sequence
+                .and_then(|sequence| {
+                    stops
+                        .iter()
+                        .position(|(stop_sequence, _)| *stop_sequence == sequence)
+                })
+                .or_else(|| {
+                    let stop_id = vehicle.stop_id.as_ref()?;
+                    stops.iter().position(|(_, stop)| stop == stop_id)
+                })
+            else {
+                continue;
+            };
+            // A stop can be visited more than once, so the next visit from the current stop is
+            // counted to.
+            let Some(target) = stops[current..]
+                .iter()
+                .position(|(_, stop)| *stop == subscription.stop_id)
+                .map(|position| current + position)
+            else {
+                continue;
+            };
+            let update = trip_update.stop_time_update.iter().find(|update| {
+                update.stop_sequence == Some(stops[target].0)
+                    || update.stop_sequence.is_none()
+                        && update.stop_id.as_ref() == Some(&subscription.stop_id)
+            });
+            // A vehicle in transit to its current stop still has to reach it, so it counts as one
+            // of the stops away; a vehicle stopped at it does not.
+            let stops_away = match vehicle.current_status() {
+                VehicleStopStatus::StoppedAt => target.checked_sub(current),
+                _ => (target + 1).checked_sub(current),
+            }
+            .and_then(|stops_away| u32::try_from(stops_away).ok());
+            let Some(stops_away) =
+                stops_away.filter(|stops_away| (1..=subscription.threshold).contains(stops_away))
+            else {
+                continue;
+            };
+            // A trip update without a start date is taken to be for today's run of the trip.
+            let start_date = trip_update
+                .trip
+                .start_date
+                .clone()
+                .unwrap_or_else(|| today().format("%Y%m%d").to_string());
+            let key = notified_key(id, trip_id, &start_date);
+            match store.get(&key).text().await {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(error) => {
+                    console_error!(
+                        "Error while reading the notification marker `{key}`:\n\n{error:#?}"
+                    );
+                    continue;
+                }
+            }
+            let notification = Notification {
+                subscription_id: id,
+                stop_id: &subscription.stop_id,
+                route_id: &subscription.route_id,
+                trip_id,
+                vehicle_id: vehicle
+                    .vehicle
+                    .as_ref()
+                    .and_then(|vehicle| vehicle.id.as_deref()),
+                stops_away,
+                predicted_arrival: update
+                    .and_then(|update| update.arrival)
+                    .and_then(|arrival| arrival.time)
+                    .and_then(DateTime::from_timestamp_secs)
+                    .map(|arrival| arrival.with_timezone(&tz)),
+            };
+            let body = match to_string(&notification) {
+                Ok(body) => body,
+                Err(error) => {
+                    console_error!("Error while serializing a notification:\n\n{error:#?}");
+                    continue;
+                }
+            };
+            match client
+                .post(&subscription.callback_url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                Ok(_) => {}
+                Err(error) => {
+                    console_error!("Error while calling the webhook for the subscription `{id}`:\n\n{error:#?}");
+                    continue;
+                }
+            }
+            let result = match store.put(&key, "1") {
+                Ok(put) => put.expiration_ttl(NOTIFIED_TTL).execute().await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                console_error!("Error while saving the notification marker `{key}`:\n\n{error:#?}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::gtfs::{Schedule, Stop, StopTime, Transfer, Trip, NOT_POSSIBLE};
+
+#[derive(Serialize)]
+struct Connection {
+    from_stop: Stop,
+    to_stop: Stop,
+    transfer_type: Option<u8>,
+    min_transfer_time: Option<u32>,
+}
+
+pub async fn transfers(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(agency) = pairs.get("agency") else {
+        return Response::error("Missing `agency` parameter.", 400);
+    };
+    let Some(from_route_id) = pairs.get("from_route_id") else {
+        return Response::error("Missing `from_route_id` parameter.", 400);
+    };
+    let Some(to_route_id) = pairs.get("to_route_id") else {
+        return Response::error("Missing `to_route_id` parameter.", 400);
+    };
+    let mut schedule = match Schedule::fetch(&context.env, agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let trips: HashMap<_, _> = match schedule.read_filtered::<Trip>("trips.txt", |trip| {
+        trip.route_id == *from_route_id || trip.route_id == *to_route_id
+    }) {
+        Ok(trips) => trips
+            .into_iter()
+            .map(|trip| (trip.trip_id, trip.route_id))
+            .collect(),
+        Err(response) => return response,
+    };
+    let mut from_stops = HashSet::new();
+    let mut to_stops = HashSet::new();
+    if let Err(response) = schedule.read_filtered::<StopTime>("stop_times.txt", |stop_time| {
+        if let Some(route_id) = trips.get(&stop_time.trip_id) {
+            if route_id == from_route_id {
+                from_stops.insert(stop_time.stop_id.clone());
+            }
+            if route_id == to_route_id {
+                to_stops.insert(stop_time.stop_id.clone());
+            }
+        }
+        false
+    }) {
+        return response;
+    }
+    if from_stops.is_empty() || to_stops.is_empty() {
+        return Response::error(format!("No trips in the 'trips.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API match both the route IDs `{from_route_id}` and `{to_route_id}`."), 400);
+    }
+    let transfers = if schedule.contains("transfers.txt") {
+        match schedule.read_filtered::<Transfer>("transfers.txt", |transfer| {
+            from_stops.contains(&transfer.from_stop_id)
+                && to_stops.contains(&transfer.to_stop_id)
+                && transfer
+                    .from_route_id
+                    .as_ref()
+                    .is_none_or(|route_id| route_id == from_route_id)
+                && transfer
+                    .to_route_id
+                    .as_ref()
+                    .is_none_or(|route_id| route_id == to_route_id)
+        }) {
+            Ok(transfers) => transfers,
+            Err(response) => return response,
+        }
+    } else {
+        Vec::new()
+    };
+    let stops: HashMap<_, _> = match schedule.read_filtered::<Stop>("stops.txt", |stop| {
+        from_stops.contains(&stop.id) || to_stops.contains(&stop.id)
+    }) {
+        Ok(stops) => stops
+            .into_iter()
+            .map(|stop| (stop.id.clone(), stop))
+            .collect(),
+        Err(response) => return response,
+    };
+    // Without a record in `transfers.txt`, the routes can still be connected at a stop they both
+    // serve, or between platforms of the same station.
+    let mut connections: HashMap<_, _> = from_stops
+        .iter()
+        .filter_map(|id| stops.get(id))
+        .cartesian_product(to_stops.iter().filter_map(|id| stops.get(id)))
+        .filter(|(from, to)| {
+            from.id == to.id
+                || from
+                    .parent_station
+                    .as_ref()
+                    .is_some_and(|station| to.parent_station.as_ref() == Some(station))
+        })
+        .map(|(from, to)| ((from.id.as_str(), to.id.as_str()), (None, None)))
+        .collect();
+    // Route-specific records take priority over records for every route.
+    for transfer in transfers.iter().sorted_by_key(|transfer| {
+        transfer.from_route_id.is_some() || transfer.to_route_id.is_some()
+    }) {
+        connections.insert(
+            (&transfer.from_stop_id, &transfer.to_stop_id),
+            (transfer.transfer_type, transfer.min_transfer_time),
+        );
+    }
+    let connections = connections
+        .into_iter()
+        .filter(|(_, (transfer_type, _))| *transfer_type != Some(NOT_POSSIBLE))
+        .filter_map(|((from, to), (transfer_type, min_transfer_time))| {
+            Some(Connection {
+                from_stop: stops.get(from)?.clone(),
+                to_stop: stops.get(to)?.clone(),
+                transfer_type,
+                min_transfer_time,
+            })
+        })
+        .sorted_unstable_by(|a, b| {
+            a.min_transfer_time
+                .unwrap_or_default()
+                .cmp(&b.min_transfer_time.unwrap_or_default())
+                .then_with(|| a.from_stop.name.cmp(&b.from_stop.name))
+        })
+        .collect_vec();
+    Response::from_json(&connections)
+}
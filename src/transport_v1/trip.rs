@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, TimeDelta};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use serde::Serialize;
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    common::{
+        realtime::{
+            trip_update::stop_time_update::ScheduleRelationship, trip_update::StopTimeEvent,
+        },
+        service_time, stop_delays, timezone, TRIP_UPDATES,
+    },
+    gtfs::{parse_time, Route, Schedule, Service, Stop, StopTime, Trip},
+    index::Index,
+};
+
+#[derive(Serialize)]
+struct TripResult {
+    trip_id: String,
+    headsign: Option<String>,
+    direction_id: Option<u8>,
+    route: Option<Route>,
+    service: Option<Service>,
+    stops: Vec<TripStop>,
+    updated_at: Option<DateTime<Tz>>,
+}
+
+#[derive(Serialize)]
+struct TripStop {
+    stop_sequence: u32,
+    stop_id: String,
+    stop: Option<Stop>,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    realtime: Option<Realtime>,
+}
+
+/// Realtime estimate for one stop of a trip.
+#[derive(Serialize)]
+struct Realtime {
+    arrival: Option<DateTime<Tz>>,
+    departure: Option<DateTime<Tz>>,
+    delay_sec: Option<i32>,
+    /// Whether the vehicle will not stop here, although the timetable says it does.
+    skipped: bool,
+}
+
+/// Time in `tz` and delay of a realtime arrival or departure.
+fn event(event: Option<StopTimeEvent>, tz: Tz) -> (Option<DateTime<Tz>>, Option<i32>) {
+    event.map_or((None, None), |event| {
+        (
+            event
+                .time
+                .and_then(DateTime::from_timestamp_secs)
+                .map(|time| time.with_timezone(&tz)),
+            event.delay,
+        )
+    })
+}
+
+pub async fn trip(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let Some(trip_id) = context.param("trip_id") else {
+        return Response::error("Missing trip ID.", 400);
+    };
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let updates = match TRIP_UPDATES.fetch(&context.env).await {
+        Ok(updates) => updates,
+        Err(response) => return response,
+    };
+    let trip_update = updates
+        .entity
+        .iter()
+        .filter_map(|entity| entity.trip_update.as_ref())
+        .find(|trip_update| trip_update.trip.trip_id.as_deref() == Some(trip_id));
+    // Without an agency, the agency's timetable is found from the route of the trip's realtime
+    // update, so that only trips that are running can be looked up without one.
+    let agency = match pairs.get("agency") {
+        Some(agency) => agency.clone(),
+        None => {
+            let Some(route_id) =
+                trip_update.and_then(|trip_update| trip_update.trip.route_id.as_ref())
+            else {
+                return Response::error(
+                    "Missing `agency` parameter, which is needed for trips that are not running.",
+                    400,
+                );
+            };
+            let index = match Index::load(&context.env).await {
+                Ok(index) => index,
+                Err(response) => return response,
+            };
+            let Some(contract) = index.contract_of(route_id) else {
+                return Response::error(
+                    format!("Missing `agency` parameter, which is needed as the trip's route `{route_id}` is not indexed."),
+                    400,
+                );
+            };
+            contract.to_string()
+        }
+    };
+    let mut schedule = match Schedule::fetch(&context.env, &agency).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+    let trip = match schedule.read_filtered::<Trip>("trips.txt", |trip| trip.trip_id == *trip_id) {
+        Ok(trips) => trips.into_iter().next(),
+        Err(response) => return response,
+    };
+    let Some(trip) = trip else {
+        return Response::error(format!("No trips in the 'trips.txt' file from the Transport Open Data 'Public Transport - Timetables - For Realtime' API match the trip ID `{trip_id}`."), 404);
+    };
+    let route =
+        match schedule.read_filtered::<Route>("routes.txt", |route| route.id == trip.route_id) {
+            Ok(routes) => routes.into_iter().next(),
+            Err(response) => return response,
+        };
+    let service = match schedule.read_services(&HashSet::from([trip.service_id.as_str()])) {
+        Ok(mut services) => services.remove(&trip.service_id),
+        Err(response) => return response,
+    };
+    let stop_times = match schedule
+        .read_filtered::<StopTime>("stop_times.txt", |stop_time| stop_time.trip_id == *trip_id)
+    {
+        Ok(stop_times) => stop_times
+            .into_iter()
+            .sorted_unstable_by_key(|stop_time| stop_time.stop_sequence)
+            .collect_vec(),
+        Err(response) => return response,
+    };
+    let wanted: HashSet<_> = stop_times
+        .iter()
+        .map(|stop_time| &stop_time.stop_id)
+        .collect();
+    let stops: HashMap<_, _> =
+        match schedule.read_filtered::<Stop>("stops.txt", |stop| wanted.contains(&stop.id)) {
+            Ok(stops) => stops
+                .into_iter()
+                .map(|stop| (stop.id.clone(), stop))
+                .collect(),
+            Err(response) => return response,
+        };
+    #[allow(clippy::cast_possible_wrap)]
+    let updated_at = trip_update
+        .and_then(|trip_update| trip_update.timestamp)
+        .map(|timestamp| timestamp as i64)
+        .and_then(DateTime::from_timestamp_secs)
+        .map(|updated_at| updated_at.with_timezone(&tz));
+    // Stops without an estimate of their own are given the delay of the last stop before them that
+    // has one, from the service day the trip started on.
+    let delays = trip_update.map(|trip_update| {
+        let stops = stop_times
+            .iter()
+            .map(|stop_time| (Some(stop_time.stop_sequence), stop_time.stop_id.as_str()))
+            .collect_vec();
+        stop_delays(trip_update, &stops)
+    });
+    let start_date = trip_update
+        .and_then(|trip_update| trip_update.trip.start_date.as_deref())
+        .and_then(|start_date| NaiveDate::parse_from_str(start_date, "%Y%m%d").ok());
+    let delayed = |time: Option<&str>, delay: Option<i32>| {
+        let time =
+            service_time(start_date?, parse_time(time?)?)? + TimeDelta::seconds(delay?.into());
+        Some(time.with_timezone(&tz))
+    };
+    let stops = stop_times
+        .into_iter()
+        .enumerate()
+        .map(|(position, stop_time)| {
+            // Updates are matched by sequence where given, as a stop can be visited more than once.
+            let update = trip_update.and_then(|trip_update| {
+                trip_update.stop_time_update.iter().find(|update| {
+                    update.stop_sequence.map_or_else(
+                        || update.stop_id.as_ref() == Some(&stop_time.stop_id),
+                        |sequence| sequence == stop_time.stop_sequence,
+                    )
+                })
+            });
+            let delay = delays
+                .as_ref()
+                .map(|delays| delays[position])
+                .unwrap_or_default();
+            let realtime = match update {
+                Some(update) => {
+                    let (arrival, arrival_delay) = event(update.arrival, tz);
+                    let (departure, departure_delay) = event(update.departure, tz);
+                    Some(Realtime {
+                        arrival,
+                        departure,
+                        delay_sec: arrival_delay.or(departure_delay),
+                        skipped: update.schedule_relationship() == ScheduleRelationship::Skipped,
+                    })
+                }
+                None => delay.arrival.map(|delay_sec| Realtime {
+                    arrival: delayed(stop_time.arrival_time.as_deref(), delay.arrival),
+                    departure: delayed(stop_time.departure_time.as_deref(), delay.departure),
+                    delay_sec: Some(delay_sec),
+                    skipped: false,
+                }),
+            };
+            TripStop {
+                stop_sequence: stop_time.stop_sequence,
+                stop: stops.get(&stop_time.stop_id).cloned(),
+                stop_id: stop_time.stop_id,
+                arrival_time: stop_time.arrival_time,
+                departure_time: stop_time.departure_time,
+                realtime,
+            }
+        })
+        .collect();
+    Response::from_json(&TripResult {
+        trip_id: trip.trip_id,
+        headsign: trip.trip_headsign,
+        direction_id: trip.direction_id,
+        route,
+        service,
+        stops,
+        updated_at,
+    })
+}
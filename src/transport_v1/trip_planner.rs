@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    common::{date, time, timezone, Mode},
+    upstream::Upstream,
+};
+
+/// Number of journeys asked of the trip planner when no `count` is given.
+const DEFAULT_COUNT: usize = 5;
+
+/// Most journeys asked of the trip planner by one request.
+const MAXIMUM_COUNT: usize = 10;
+
+#[derive(Deserialize)]
+struct TripsResult {
+    #[serde(default)]
+    journeys: Vec<Journey>,
+}
+
+#[derive(Deserialize)]
+struct Journey {
+    #[serde(default)]
+    legs: Vec<Leg>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Leg {
+    duration: Option<u32>,
+    distance: Option<u32>,
+    #[serde(default)]
+    is_realtime_controlled: bool,
+    origin: Location,
+    destination: Location,
+    transportation: Option<Transportation>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    id: Option<String>,
+    name: Option<String>,
+    disassembled_name: Option<String>,
+    r#type: Option<String>,
+    coord: Option<(f64, f64)>,
+    departure_time_planned: Option<DateTime<Utc>>,
+    departure_time_estimated: Option<DateTime<Utc>>,
+    arrival_time_planned: Option<DateTime<Utc>>,
+    arrival_time_estimated: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Transportation {
+    name: Option<String>,
+    disassembled_name: Option<String>,
+    product: Option<Product>,
+    destination: Option<Destination>,
+}
+
+#[derive(Deserialize)]
+struct Product {
+    class: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct Destination {
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JourneyResult {
+    departure: Option<DateTime<Tz>>,
+    arrival: Option<DateTime<Tz>>,
+    duration_sec: Option<i64>,
+    transfers: usize,
+    legs: Vec<LegResult>,
+}
+
+#[derive(Serialize)]
+struct LegResult {
+    mode: Mode,
+    /// Short name of the line, such as `T1` or `333`.
+    route: Option<String>,
+    /// Full name of the line, such as `Sydney Buses Network 333`.
+    name: Option<String>,
+    headsign: Option<String>,
+    from: PlaceResult,
+    to: PlaceResult,
+    duration_sec: Option<u32>,
+    /// Distance walked, for walking legs.
+    distance_m: Option<u32>,
+    /// Whether the estimated times are kept up to date from realtime data.
+    realtime: bool,
+}
+
+#[derive(Serialize)]
+struct PlaceResult {
+    id: Option<String>,
+    name: Option<String>,
+    /// Platform, stand or wharf within a station, such as `Platform 16`.
+    platform: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    /// Scheduled and estimated times of leaving or arriving at the place.
+    planned: Option<DateTime<Tz>>,
+    estimated: Option<DateTime<Tz>>,
+}
+
+impl PlaceResult {
+    fn new(
+        location: Location,
+        planned: Option<DateTime<Utc>>,
+        estimated: Option<DateTime<Utc>>,
+        tz: Tz,
+    ) -> Self {
+        Self {
+            id: location.id,
+            name: location.name,
+            platform: location
+                .disassembled_name
+                .filter(|_| location.r#type.as_deref() == Some("platform")),
+            latitude: location.coord.map(|(latitude, _)| latitude),
+            longitude: location.coord.map(|(_, longitude)| longitude),
+            planned: planned.map(|planned| planned.with_timezone(&tz)),
+            estimated: estimated.map(|estimated| estimated.with_timezone(&tz)),
+        }
+    }
+}
+
+impl LegResult {
+    fn new(leg: Leg, tz: Tz) -> Self {
+        let mode = Mode::from_class(
+            leg.transportation
+                .as_ref()
+                .and_then(|transportation| transportation.product.as_ref())
+                .and_then(|product| product.class),
+        );
+        let transportation = leg.transportation.filter(|_| mode != Mode::Walk);
+        let origin = leg.origin;
+        let (departure_planned, departure_estimated) = (
+            origin.departure_time_planned,
+            origin.departure_time_estimated,
+        );
+        let destination = leg.destination;
+        let (arrival_planned, arrival_estimated) = (
+            destination.arrival_time_planned,
+            destination.arrival_time_estimated,
+        );
+        Self {
+            mode,
+            route: transportation
+                .as_ref()
+                .and_then(|transportation| transportation.disassembled_name.clone()),
+            name: transportation
+                .as_ref()
+                .and_then(|transportation| transportation.name.clone()),
+            headsign: transportation
+                .and_then(|transportation| transportation.destination)
+                .and_then(|destination| destination.name),
+            from: PlaceResult::new(origin, departure_planned, departure_estimated, tz),
+            to: PlaceResult::new(destination, arrival_planned, arrival_estimated, tz),
+            duration_sec: leg.duration,
+            distance_m: leg.distance.filter(|_| mode == Mode::Walk),
+            realtime: leg.is_realtime_controlled,
+        }
+    }
+}
+
+impl JourneyResult {
+    fn new(journey: Journey, tz: Tz) -> Self {
+        let legs: Vec<LegResult> = journey
+            .legs
+            .into_iter()
+            .map(|leg| LegResult::new(leg, tz))
+            .collect();
+        let departure = legs.first().and_then(|leg| leg.from.planned);
+        let arrival = legs.last().and_then(|leg| leg.to.planned);
+        Self {
+            departure,
+            arrival,
+            duration_sec: departure
+                .zip(arrival)
+                .map(|(departure, arrival)| (arrival - departure).num_seconds()),
+            transfers: legs
+                .iter()
+                .filter(|leg| !matches!(leg.mode, Mode::Walk | Mode::Cycle))
+                .count()
+                .saturating_sub(1),
+            legs,
+        }
+    }
+}
+
+/// The trip planner's type and name for a place written as a stop ID or as `lat,lon`.
+fn location(text: &str) -> (&'static str, String) {
+    if let Some((latitude, longitude)) = text.split_once(',')
+        && let (Ok(latitude), Ok(longitude)) = (
+            latitude.trim().parse::<f64>(),
+            longitude.trim().parse::<f64>(),
+        )
+    {
+        return ("coord", format!("{longitude}:{latitude}:EPSG:4326"));
+    }
+    ("any", text.to_string())
+}
+
+pub async fn trip_planner(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(from) = pairs.get("from") else {
+        return Response::error("Missing `from` parameter.", 400);
+    };
+    let Some(to) = pairs.get("to") else {
+        return Response::error("Missing `to` parameter.", 400);
+    };
+    let arrive_by = match pairs.get("arrive_by").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => {
+            return Response::error("The `arrive_by` parameter must be `true` or `false`.", 400);
+        }
+    };
+    let date = match date(&pairs, "date") {
+        Ok(date) => date,
+        Err(response) => return response,
+    };
+    let time = match time(&pairs, "time") {
+        Ok(time) => time,
+        Err(response) => return response,
+    };
+    let count = match pairs.get("count").map(|count| count.parse::<usize>()) {
+        None => DEFAULT_COUNT,
+        Some(Ok(count)) if (1..=MAXIMUM_COUNT).contains(&count) => count,
+        Some(_) => {
+            return Response::error(
+                format!("The `count` parameter must be a number from 1 to {MAXIMUM_COUNT}."),
+                400,
+            );
+        }
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let (type_origin, name_origin) = location(from);
+    let (type_destination, name_destination) = location(to);
+    let mut query = vec![
+        ("outputFormat", "rapidJSON".to_string()),
+        ("coordOutputFormat", "EPSG:4326".to_string()),
+        (
+            "depArrMacro",
+            if arrive_by { "arr" } else { "dep" }.to_string(),
+        ),
+        ("type_origin", type_origin.to_string()),
+        ("name_origin", name_origin),
+        ("type_destination", type_destination.to_string()),
+        ("name_destination", name_destination),
+        ("calcNumberOfTrips", count.to_string()),
+        ("TfNSWTR", "true".to_string()),
+        ("version", "10.2.1.42".to_string()),
+    ];
+    // Without a date or time the trip planner plans from now.
+    if let Some(date) = date {
+        query.push(("itdDate", date.format("%Y%m%d").to_string()));
+    }
+    if let Some(time) = time {
+        query.push((
+            "itdTime",
+            format!("{:02}{:02}", time / 3600, time / 60 % 60),
+        ));
+    }
+    let result: TripsResult = match Upstream::new("Trip Planner APIs", "v1/tp/trip")
+        .queries(query)
+        .json(&context.env)
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let journeys: Vec<JourneyResult> = result
+        .journeys
+        .into_iter()
+        .map(|journey| JourneyResult::new(journey, tz))
+        .collect();
+    Response::from_json(&journeys)
+}
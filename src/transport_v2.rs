@@ -1,26 +1,36 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate};
+use chrono_tz::Tz;
 use itertools::Itertools;
-use prost::Message;
-use reqwest::Client;
 use serde::Serialize;
 use serde_json::to_string;
-use tap::Pipe;
 use worker::{Request, Response, RouteContext};
 
-use crate::common::{add_auth_header, realtime::FeedMessage};
+use crate::{
+    common::{gtfs_time, realtime::FeedMessage, timezone, TIMEZONE, TRIP_UPDATES},
+    school::SchoolDayFilter,
+};
+
+mod stream;
+
+pub use stream::times_stream;
 
 #[derive(Serialize)]
 struct TimeResult {
-    arrival: DateTime<Utc>,
+    arrival: DateTime<Tz>,
     delay_sec: i32,
+    /// Service day that the trip runs on, which is the day before `arrival` for trips that run
+    /// past midnight.
+    service_date: NaiveDate,
+    /// `arrival` as a GTFS time of day on `service_date`, such as `25:10:00`.
+    service_time: Option<String>,
 }
 
 #[derive(Serialize)]
 struct TimesResult {
     times: Vec<TimeResult>,
-    updated_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Tz>>,
 }
 
 pub async fn times(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
@@ -35,42 +45,36 @@ pub async fn times(request: Request, context: RouteContext<()>) -> worker::Resul
     let Some(route_id) = pairs.get("route_id") else {
         return Response::error("Missing `route_id` parameter.", 400);
     };
-    let Some(add_auth_header) = add_auth_header(&context.env) else {
-        return Response::error("Missing API key.", 500);
+    let school_day = match SchoolDayFilter::from_pairs(&pairs) {
+        Ok(school_day) => school_day,
+        Err(response) => return response,
     };
-    let response = match Client::new()
-        .get("https://api.transport.nsw.gov.au/v1/gtfs/realtime/buses")
-        .pipe(add_auth_header)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(error) => {
-            return Response::error(
-                format!(
-                    "Error while sending a request to the Transport Open Data 'Public Transport - Realtime Trip Updates API' API:\n\n{error:#?}"
-                ),
-                500,
-            );
-        }
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
     };
-    let bytes = match response.bytes().await {
-        Ok(text) => text,
-        Err(error) => {
-            return Response::error(
-                format!(
-                    "Error while reading bytes from the response from the Transport Open Data 'Public Transport - Realtime Trip Updates API' API:\n\n{error:#?}"
-                ),
-                500,
-            );
-        }
+    let message = match TRIP_UPDATES.fetch(&context.env).await {
+        Ok(message) => message,
+        Err(response) => return response,
     };
-    let Ok(message) = FeedMessage::decode(bytes) else {
-        return Response::error(
-            "Error while decoding the response from the Transport Open Data 'Public Transport - Realtime Trip Updates API' API.",
+    let mut result = departures(&message, stop_id, route_id, tz);
+    if let Some(school_day) = school_day
+        && let Err(response) = school_day.retain(&mut result.times, |time| Some(time.service_date))
+    {
+        return response;
+    }
+    match to_string(&result) {
+        Ok(json) => Response::ok(json),
+        Err(error) => Response::error(
+            format!("Error while serializing a response: {error:#?}"),
             500,
-        );
-    };
+        ),
+    }
+}
+
+/// Departures from the stop `stop_id` on the route `route_id` in a trip updates feed, with times
+/// in the time zone `tz`.
+fn departures(message: &FeedMessage, stop_id: &str, route_id: &str, tz: Tz) -> TimesResult {
     let mut latest = None;
     let times = message
         .entity
@@ -104,20 +108,28 @@ pub async fn times(request: Request, context: RouteContext<()>) -> worker::Resul
                                 .is_some_and(|id| *id == *route_id)
                                 && update.stop_id.as_ref().is_some_and(|id| *id == *stop_id)
                         })
-                        .map(|(arrival, delay_sec)| TimeResult { arrival, delay_sec })
+                        .map(|(arrival, delay_sec)| {
+                            // Trips without a start date are taken to run on the day they arrive.
+                            let service_date = trip_update
+                                .trip
+                                .start_date
+                                .as_ref()
+                                .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+                                .unwrap_or_else(|| arrival.with_timezone(&TIMEZONE).date_naive());
+                            TimeResult {
+                                arrival: arrival.with_timezone(&tz),
+                                delay_sec,
+                                service_date,
+                                service_time: gtfs_time(service_date, arrival),
+                            }
+                        })
                 })
             })
         })
         .flatten()
         .collect_vec();
-    match to_string(&TimesResult {
+    TimesResult {
         times,
-        updated_at: latest,
-    }) {
-        Ok(json) => Response::ok(json),
-        Err(error) => Response::error(
-            format!("Error while serializing a response: {error:#?}"),
-            500,
-        ),
+        updated_at: latest.map(|latest| latest.with_timezone(&tz)),
     }
 }
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono_tz::Tz;
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use serde_json::to_string;
+use worker::{
+    async_trait, console_error, durable_object, Env, Headers, Request, Response, RouteContext,
+    State,
+};
+
+use super::departures;
+use crate::common::{realtime::FeedMessage, timezone, TRIP_UPDATES};
+
+/// Name of the Durable Object binding for [`Departures`].
+const BINDING: &str = "DEPARTURES";
+
+/// How often the shared trip updates feed is polled while anyone is subscribed.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+pub async fn times_stream(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url.query_pairs().collect();
+    if !pairs.contains_key("stop_id") {
+        return Response::error("Missing `stop_id` parameter.", 400);
+    }
+    if !pairs.contains_key("route_id") {
+        return Response::error("Missing `route_id` parameter.", 400);
+    }
+    // Every subscriber shares one object, and so one poll of the feed, since the feed covers
+    // every bus regardless of stop or route.
+    let stub = match context
+        .env
+        .durable_object(BINDING)
+        .and_then(|namespace| namespace.id_from_name("buses").and_then(|id| id.get_stub()))
+    {
+        Ok(stub) => stub,
+        Err(error) => {
+            return Response::error(
+                format!("Error while opening the departures stream:\n\n{error:#?}"),
+                500,
+            );
+        }
+    };
+    stub.fetch_with_request(request).await
+}
+
+struct Subscriber {
+    stop_id: String,
+    route_id: String,
+    tz: Tz,
+    sender: UnboundedSender<worker::Result<Vec<u8>>>,
+    /// The departures last sent, so that unchanged departures are not sent again.
+    last: Option<String>,
+}
+
+impl Subscriber {
+    /// Send the departures in `feed` if they have changed since they were last sent, or a comment
+    /// to keep the connection open otherwise.
+    fn send(&mut self, feed: &FeedMessage) {
+        let result = departures(feed, &self.stop_id, &self.route_id, self.tz);
+        let (times, json) = match to_string(&result.times)
+            .and_then(|times| to_string(&result).map(|json| (times, json)))
+        {
+            Ok(result) => result,
+            Err(error) => {
+                console_error!("Error while serializing departures:\n\n{error:#?}");
+                return;
+            }
+        };
+        let event = if self.last.as_ref() == Some(&times) {
+            ":\n\n".to_string()
+        } else {
+            self.last = Some(times);
+            format!("data: {json}\n\n")
+        };
+        // Fails only once the client has gone, and the subscriber is dropped after this poll.
+        let _ = self.sender.unbounded_send(Ok(event.into_bytes()));
+    }
+}
+
+/// Shares one poll of the trip updates feed between every open departures stream, and pushes
+/// each stream its departures whenever they change.
+#[durable_object]
+pub struct Departures {
+    state: State,
+    env: Env,
+    subscribers: Vec<Subscriber>,
+    feed: Option<FeedMessage>,
+}
+
+#[durable_object]
+impl DurableObject for Departures {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            state,
+            env,
+            subscribers: Vec::new(),
+            feed: None,
+        }
+    }
+
+    async fn fetch(&mut self, request: Request) -> worker::Result<Response> {
+        let url = request.url()?;
+        let pairs: HashMap<_, _> = url
+            .query_pairs()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let (Some(stop_id), Some(route_id)) = (pairs.get("stop_id"), pairs.get("route_id")) else {
+            return Response::error("Missing `stop_id` or `route_id` parameter.", 400);
+        };
+        let tz = match timezone(&pairs) {
+            Ok(tz) => tz,
+            Err(response) => return response,
+        };
+        let (sender, receiver) = unbounded();
+        let mut subscriber = Subscriber {
+            stop_id: stop_id.clone(),
+            route_id: route_id.clone(),
+            tz,
+            sender,
+            last: None,
+        };
+        if let Some(feed) = &self.feed {
+            subscriber.send(feed);
+        }
+        self.subscribers.push(subscriber);
+        let storage = self.state.storage();
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(Duration::ZERO).await?;
+        }
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "text/event-stream")?;
+        headers.set("Cache-Control", "no-cache")?;
+        Ok(Response::from_stream(receiver)?.with_headers(headers))
+    }
+
+    async fn alarm(&mut self) -> worker::Result<Response> {
+        self.subscribers
+            .retain(|subscriber| !subscriber.sender.is_closed());
+        if self.subscribers.is_empty() {
+            self.feed = None;
+            return Response::empty();
+        }
+        // The next poll is scheduled before anything that can fail, so that an error here does not
+        // stop the stream for good.
+        self.state.storage().set_alarm(POLL_INTERVAL).await?;
+        match TRIP_UPDATES.fetch(&self.env).await {
+            Ok(feed) => {
+                for subscriber in &mut self.subscribers {
+                    subscriber.send(&feed);
+                }
+                self.feed = Some(feed);
+            }
+            Err(Ok(mut response)) => {
+                if let Ok(text) = response.text().await {
+                    console_error!("{text}");
+                }
+            }
+            Err(Err(error)) => console_error!("{error:#?}"),
+        }
+        Response::empty()
+    }
+}
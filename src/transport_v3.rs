@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    common::{fetch_departures, timezone, DepartureTime, Mode, TIMEZONE},
+    school::SchoolDayFilter,
+    upstream,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Departures {
+    #[serde(default)]
+    stop_events: Vec<StopEvent>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StopEvent {
+    #[serde(default)]
+    is_realtime_controlled: bool,
+    #[serde(default)]
+    is_cancelled: bool,
+    #[serde(default)]
+    realtime_status: Vec<String>,
+    location: Option<Location>,
+    departure_time_planned: DateTime<Utc>,
+    departure_time_estimated: Option<DateTime<Utc>>,
+    transportation: Option<Transportation>,
+    properties: Option<EventProperties>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    disassembled_name: Option<String>,
+    r#type: Option<String>,
+    properties: Option<LocationProperties>,
+}
+
+#[derive(Deserialize)]
+struct LocationProperties {
+    occupancy: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Transportation {
+    disassembled_name: Option<String>,
+    description: Option<String>,
+    product: Option<Product>,
+    destination: Option<Destination>,
+}
+
+#[derive(Deserialize)]
+struct Product {
+    class: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct Destination {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EventProperties {
+    #[serde(rename = "RealtimeTripId")]
+    realtime_trip_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Departure {
+    /// Planned departure in the time zone of the `tz` parameter.
+    planned: DateTime<Tz>,
+    /// Estimated departure in the time zone of the `tz` parameter.
+    estimated: Option<DateTime<Tz>>,
+    /// Seconds that the departure is estimated to be late, or early if negative.
+    delay_sec: Option<i64>,
+    mode: Mode,
+    /// Short name of the line, such as `601`.
+    route: Option<String>,
+    /// Description of the line, such as `Parramatta to Rouse Hill Station via Hills Showground`.
+    description: Option<String>,
+    destination: Option<String>,
+    /// Platform or stand that the vehicle leaves from, such as `Stand B4`.
+    platform: Option<String>,
+    /// Whether `estimated` is kept up to date from realtime data.
+    realtime: bool,
+    /// How full the vehicle is, either `many_seats`, `few_seats` or `standing_only`.
+    occupancy: Option<String>,
+    cancelled: bool,
+    /// ID of the trip in the realtime feeds, as used by `/v1/transport/trips/{trip_id}`.
+    trip_id: Option<String>,
+}
+
+impl Departure {
+    /// The departure `event`, with local times in the time zone `tz`.
+    fn new(event: StopEvent, tz: Tz) -> Self {
+        let (platform, occupancy) = event.location.map_or((None, None), |location| {
+            (
+                location
+                    .disassembled_name
+                    .filter(|_| location.r#type.as_deref() == Some("platform")),
+                location
+                    .properties
+                    .and_then(|properties| properties.occupancy)
+                    .map(|occupancy| occupancy.to_lowercase()),
+            )
+        });
+        let transportation = event.transportation;
+        Self {
+            planned: event.departure_time_planned.with_timezone(&tz),
+            estimated: event
+                .departure_time_estimated
+                .map(|estimated| estimated.with_timezone(&tz)),
+            delay_sec: event
+                .departure_time_estimated
+                .map(|estimated| (estimated - event.departure_time_planned).num_seconds()),
+            mode: Mode::from_class(
+                transportation
+                    .as_ref()
+                    .and_then(|transportation| transportation.product.as_ref())
+                    .and_then(|product| product.class),
+            ),
+            route: transportation
+                .as_ref()
+                .and_then(|transportation| transportation.disassembled_name.clone()),
+            description: transportation
+                .as_ref()
+                .and_then(|transportation| transportation.description.clone()),
+            destination: transportation
+                .and_then(|transportation| transportation.destination)
+                .and_then(|destination| destination.name),
+            platform,
+            realtime: event.is_realtime_controlled,
+            occupancy,
+            cancelled: event.is_cancelled
+                || event
+                    .realtime_status
+                    .iter()
+                    .any(|status| status == "CANCELLED"),
+            trip_id: event
+                .properties
+                .and_then(|properties| properties.realtime_trip_id),
+        }
+    }
+}
+
+pub async fn times(request: Request, context: RouteContext<()>) -> worker::Result<Response> {
+    let url = request.url()?;
+    let pairs: HashMap<_, _> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let Some(id) = pairs.get("id") else {
+        return Response::error("Missing `id` parameter.", 400);
+    };
+    let id = match upstream::id("id", id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let school_day = match SchoolDayFilter::from_pairs(&pairs) {
+        Ok(school_day) => school_day,
+        Err(response) => return response,
+    };
+    let modes = match Mode::from_pairs(&pairs) {
+        Ok(modes) => modes,
+        Err(response) => return response,
+    };
+    let when = match DepartureTime::from_pairs(&pairs) {
+        Ok(when) => when,
+        Err(response) => return response,
+    };
+    let tz = match timezone(&pairs) {
+        Ok(tz) => tz,
+        Err(response) => return response,
+    };
+    let result: Departures = match fetch_departures(&context.env, id, &modes, when).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    let mut departures = result
+        .stop_events
+        .into_iter()
+        .map(|event| Departure::new(event, tz))
+        .collect_vec();
+    // The departure monitor gives no service day, so departures are filtered by the
+    // Sydney date of their planned time.
+    if let Some(school_day) = school_day
+        && let Err(response) = school_day.retain(&mut departures, |departure| {
+            Some(departure.planned.with_timezone(&TIMEZONE).date_naive())
+        })
+    {
+        return response;
+    }
+    Response::from_json(&departures)
+}
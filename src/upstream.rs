@@ -0,0 +1,118 @@
+//! Requests to the Transport Open Data APIs. Each request is built with [`Upstream`], which encodes
+//! every path segment and query parameter separately so that input from a caller can only ever be
+//! the one value that it was given as, and never add parameters or path segments of its own.
+
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde_json::from_str;
+use tap::Pipe;
+use worker::{Env, Response};
+
+use crate::common::add_auth_header;
+
+/// Base URL of the Transport Open Data APIs.
+const BASE_URL: &str = "https://api.transport.nsw.gov.au/";
+
+/// Longest ID accepted by [`id`].
+const MAXIMUM_ID_LENGTH: usize = 128;
+
+/// A request to one of the Transport Open Data APIs.
+pub struct Upstream {
+    /// Name of the API, as used in error messages.
+    name: &'static str,
+    url: Url,
+}
+
+impl Upstream {
+    /// A request to the API `name` at `path`, which is relative to [`BASE_URL`] and must be fixed
+    /// rather than taken from input.
+    pub fn new(name: &'static str, path: &'static str) -> Self {
+        Self {
+            name,
+            url: Url::parse(BASE_URL)
+                .and_then(|base| base.join(path))
+                .expect("the path of an upstream request should be valid"),
+        }
+    }
+
+    /// Add `segment` to the end of the path, encoding any `/`, `?` or `#` in it.
+    pub fn segment(mut self, segment: &str) -> Self {
+        if let Ok(mut segments) = self.url.path_segments_mut() {
+            segments.push(segment);
+        }
+        self
+    }
+
+    /// Add the query parameter `key` with `value`, encoding both.
+    pub fn query(mut self, key: &str, value: &str) -> Self {
+        self.url.query_pairs_mut().append_pair(key, value);
+        self
+    }
+
+    /// Add every query parameter in `pairs`.
+    pub fn queries<K: AsRef<str>, V: AsRef<str>>(
+        mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.url.query_pairs_mut().extend_pairs(pairs);
+        self
+    }
+
+    async fn send(self, env: &Env) -> Result<reqwest::Response, worker::Result<Response>> {
+        let Some(add_auth_header) = add_auth_header(env) else {
+            return Err(Response::error("Missing API key.", 500));
+        };
+        let name = self.name;
+        Client::new()
+            .get(self.url)
+            .pipe(add_auth_header)
+            .send()
+            .await
+            .map_err(|error| {
+                Response::error(format!("Error while sending a request to the Transport Open Data '{name}' API:\n\n{error:#?}"), 500)
+            })
+    }
+
+    pub async fn text(self, env: &Env) -> Result<String, worker::Result<Response>> {
+        let name = self.name;
+        self.send(env).await?.text().await.map_err(|error| {
+            Response::error(format!("Error while reading text from the response from the Transport Open Data '{name}' API:\n\n{error:#?}"), 500)
+        })
+    }
+
+    pub async fn json<T: DeserializeOwned>(self, env: &Env) -> Result<T, worker::Result<Response>> {
+        let name = self.name;
+        let text = self.text(env).await?;
+        from_str(&text).map_err(|error| {
+            Response::error(format!("Error while parsing JSON from the response from the Transport Open Data '{name}' API:\n\n{error:#?}"), 500)
+        })
+    }
+
+    pub async fn bytes(self, env: &Env) -> Result<Vec<u8>, worker::Result<Response>> {
+        let name = self.name;
+        match self.send(env).await?.bytes().await {
+            Ok(bytes) => Ok(bytes.into()),
+            Err(error) => Err(Response::error(format!("Error while reading bytes from the response from the Transport Open Data '{name}' API:\n\n{error:#?}"), 500)),
+        }
+    }
+}
+
+/// Check that the parameter `name` is an ID of the form that TfNSW uses, made of letters, digits,
+/// `_`, `-`, `.` and `:` with at least one letter or digit, before it is passed upstream.
+pub fn id<'a>(name: &str, value: &'a str) -> Result<&'a str, worker::Result<Response>> {
+    if value.len() <= MAXIMUM_ID_LENGTH
+        && value
+            .chars()
+            .any(|character| character.is_ascii_alphanumeric())
+        && value
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "_-.:".contains(character))
+    {
+        Ok(value)
+    } else {
+        Err(Response::error(
+            format!("The `{name}` parameter must be an ID made of letters, digits, `_`, `-`, `.` and `:`, and at most {MAXIMUM_ID_LENGTH} characters long."),
+            400,
+        ))
+    }
+}